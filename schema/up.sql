@@ -7,9 +7,23 @@ CREATE DOMAIN hostname       AS varchar(32)  CHECK (VALUE ~ '\A[-_a-z0-9]+\Z');
 CREATE DOMAIN netname        AS varchar(32)  CHECK (VALUE ~ '\A(NONE|[-_a-z0-9]+)\Z');
 CREATE DOMAIN port           AS integer      CHECK (VALUE > 0 AND VALUE <= 65536);
 CREATE DOMAIN wireguard_key  AS varchar(44)  CHECK (VALUE ~ '\A[+/A-Za-z0-9]{43}=\Z');
+-- Linux limits interface names to 15 characters
+CREATE DOMAIN ifname         AS varchar(15)  CHECK (VALUE ~ '\A[-_a-z0-9]+\Z');
+-- IPv6 requires an MTU of at least 1280
+CREATE DOMAIN wireguard_mtu  AS integer      CHECK (VALUE >= 1280 AND VALUE <= 65535);
+-- wg-quick Table = off, auto, or a routing table number or name
+CREATE DOMAIN route_table    AS varchar(32)  CHECK (VALUE ~ '\A[-_A-Za-z0-9]+\Z');
+CREATE DOMAIN fwmark         AS bigint       CHECK (VALUE >= 0 AND VALUE <= 4294967295);
+-- wg-quick reads one command per line
+CREATE DOMAIN hook_command   AS text         CHECK (VALUE !~ '\n');
 -- Match default /etc/adduser.conf NAME_REGEX
 CREATE DOMAIN username       AS varchar(32)  CHECK (VALUE ~ '\A[a-z][-a-z0-9_]{1,31}\Z');
 CREATE DOMAIN email          AS varchar(254) CHECK (VALUE ~ '\A.+@.+\Z');
+-- ssh_config keywords are case-insensitive, but are stored as given
+CREATE DOMAIN ssh_keyword    AS varchar(64)  CHECK (VALUE ~ '\A[A-Za-z][A-Za-z0-9]*\Z');
+CREATE DOMAIN ssh_argument   AS text         CHECK (VALUE <> '' AND VALUE !~ '\n');
+CREATE DOMAIN ssh_key_type   AS varchar(64)  CHECK (VALUE ~ '\A[a-z0-9][-@.a-z0-9]*\Z');
+CREATE DOMAIN ssh_public_key AS text         CHECK (VALUE ~ '\A[+/A-Za-z0-9]+={0,2}\Z');
 CREATE DOMAIN owner          AS varchar(32);
 
 -- INSERT name='NONE' to support machines that have no addresses in machine_addresses
@@ -64,14 +78,54 @@ CREATE TABLE machines (
 SELECT periods.add_system_time_period('machines', 'row_start', 'row_end');
 SELECT periods.add_system_versioning('machines');
 
+-- Address pools and default settings for WireGuard interfaces.  Machines with an interface
+-- of the same name are peers of each other.  An interface without a pool here uses the pool from
+-- WIREGUARD_IPV4_START, WIREGUARD_IPV4_END, WIREGUARD_IPV6_START, and WIREGUARD_IPV6_END.
+CREATE TABLE wireguard_overlays (
+    interface    ifname         PRIMARY KEY,
+    ipv4_start   inet           CHECK (family(ipv4_start) = 4),
+    ipv4_end     inet           CHECK (family(ipv4_end) = 4),
+    ipv6_start   inet           CHECK (family(ipv6_start) = 6),
+    ipv6_end     inet           CHECK (family(ipv6_end) = 6),
+    mtu          wireguard_mtu,
+    dns          text[],
+    route_table  route_table,
+    fwmark       fwmark,
+    pre_up       hook_command,
+    post_up      hook_command,
+    pre_down     hook_command,
+    post_down    hook_command,
+    CHECK (num_nulls(ipv4_start, ipv4_end, ipv6_start, ipv6_end) IN (0, 4))
+);
+SELECT periods.add_system_time_period('wireguard_overlays', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('wireguard_overlays');
+
 -- Separate table because not all machines have an infrabase-managed WireGuard interface
 CREATE TABLE wireguard_interfaces (
-   hostname                hostname       PRIMARY KEY REFERENCES machines,
+   hostname                hostname       NOT NULL REFERENCES machines,
+   interface               ifname         NOT NULL DEFAULT 'wg0',
    wireguard_ipv4_address  inet           NOT NULL CHECK (family(wireguard_ipv4_address) = 4),
    wireguard_ipv6_address  inet           NOT NULL CHECK (family(wireguard_ipv6_address) = 6),
    wireguard_port          port           NOT NULL,
    wireguard_privkey       wireguard_key  NOT NULL,
    wireguard_pubkey        wireguard_key  NOT NULL,
+   -- Whether other machines may route all of their traffic through this machine
+   exit_node               boolean        NOT NULL DEFAULT false,
+   -- 'mesh' machines peer with every other mesh machine, 'spoke' machines peer only with their hubs
+   wireguard_role          varchar(5)     NOT NULL DEFAULT 'mesh' CHECK (wireguard_role IN ('mesh', 'spoke')),
+   -- Settings that override the defaults in wireguard_overlays
+   mtu                     wireguard_mtu,
+   dns                     text[],
+   route_table             route_table,
+   fwmark                  fwmark,
+   pre_up                  hook_command,
+   post_up                 hook_command,
+   pre_down                hook_command,
+   post_down               hook_command,
+   PRIMARY KEY (hostname, interface),
+   UNIQUE (hostname, wireguard_port),
+   UNIQUE (interface, wireguard_ipv4_address),
+   UNIQUE (interface, wireguard_ipv6_address),
    UNIQUE (wireguard_privkey),
    UNIQUE (wireguard_pubkey)
 );
@@ -87,6 +141,85 @@ CREATE TABLE ssh_servers (
 SELECT periods.add_system_time_period('ssh_servers', 'row_start', 'row_end');
 SELECT periods.add_system_versioning('ssh_servers');
 
+-- Options such as IdentityFile or ServerAliveInterval for the Host blocks in `ssh-config`.
+-- A machine's options take precedence over those for the network of the address used to
+-- reach it, which take precedence over those for its owner.
+CREATE TABLE machine_ssh_options (
+    hostname  hostname      NOT NULL REFERENCES machines,
+    option    ssh_keyword   NOT NULL,
+    value     ssh_argument  NOT NULL,
+    PRIMARY KEY (hostname, option)
+);
+SELECT periods.add_system_time_period('machine_ssh_options', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('machine_ssh_options');
+
+CREATE TABLE network_ssh_options (
+    network  netname       NOT NULL REFERENCES networks(name),
+    option   ssh_keyword   NOT NULL,
+    value    ssh_argument  NOT NULL,
+    PRIMARY KEY (network, option)
+);
+SELECT periods.add_system_time_period('network_ssh_options', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('network_ssh_options');
+
+CREATE TABLE owner_ssh_options (
+    owner   owner         NOT NULL REFERENCES owners(owner),
+    option  ssh_keyword   NOT NULL,
+    value   ssh_argument  NOT NULL,
+    PRIMARY KEY (owner, option)
+);
+SELECT periods.add_system_time_period('owner_ssh_options', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('owner_ssh_options');
+
+-- The machine `ssh-config` uses as a ProxyJump for `hostname` when it cannot reach `hostname`
+-- directly, instead of a bastion found through network_links
+CREATE TABLE ssh_bastions (
+    hostname  hostname  PRIMARY KEY REFERENCES machines,
+    bastion   hostname  NOT NULL REFERENCES machines(hostname),
+    CHECK (hostname <> bastion)
+);
+SELECT periods.add_system_time_period('ssh_bastions', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('ssh_bastions');
+
+CREATE TABLE ssh_host_keys (
+    hostname    hostname        NOT NULL REFERENCES machines,
+    key_type    ssh_key_type    NOT NULL,
+    public_key  ssh_public_key  NOT NULL,
+    PRIMARY KEY (hostname, key_type)
+);
+SELECT periods.add_system_time_period('ssh_host_keys', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('ssh_host_keys');
+
+-- People who log into machines
+CREATE TABLE users (
+    name  username  PRIMARY KEY
+);
+SELECT periods.add_system_time_period('users', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('users');
+
+CREATE TABLE user_ssh_keys (
+    name        username        NOT NULL REFERENCES users,
+    key_type    ssh_key_type    NOT NULL,
+    public_key  ssh_public_key  PRIMARY KEY,
+    comment     ssh_argument
+);
+SELECT periods.add_system_time_period('user_ssh_keys', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('user_ssh_keys');
+
+-- Lets a user log into a machine, or into all of an owner's machines, as `login`,
+-- or as the machine's ssh_user if `login` is NULL
+CREATE TABLE ssh_grants (
+    id        int       PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+    name      username  NOT NULL REFERENCES users,
+    hostname  hostname  REFERENCES machines,
+    owner     owner     REFERENCES owners(owner),
+    login     username,
+    CHECK (num_nulls(hostname, owner) = 1)
+);
+SELECT periods.add_system_time_period('ssh_grants', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('ssh_grants');
+
+-- Applies on every WireGuard interface that both machines have
 CREATE TABLE wireguard_keepalives (
     source_machine  hostname  NOT NULL REFERENCES machines(hostname),
     target_machine  hostname  NOT NULL REFERENCES machines(hostname),
@@ -97,6 +230,34 @@ CREATE TABLE wireguard_keepalives (
 SELECT periods.add_system_time_period('wireguard_keepalives', 'row_start', 'row_end');
 SELECT periods.add_system_versioning('wireguard_keepalives');
 
+-- A machine that sends all of its traffic through `exit_machine` over `interface`.
+-- `exit_machine` must be an exit node on that interface.
+-- Only one exit node per machine because there can only be one default route.
+CREATE TABLE wireguard_exit_routes (
+    source_machine  hostname  PRIMARY KEY,
+    exit_machine    hostname  NOT NULL,
+    interface       ifname    NOT NULL DEFAULT 'wg0',
+    FOREIGN KEY (source_machine, interface) REFERENCES wireguard_interfaces(hostname, interface),
+    FOREIGN KEY (exit_machine, interface) REFERENCES wireguard_interfaces(hostname, interface),
+    CHECK (source_machine <> exit_machine)
+);
+SELECT periods.add_system_time_period('wireguard_exit_routes', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('wireguard_exit_routes');
+
+-- Mesh machines that relay traffic for a spoke machine.  The first hub (by hostname)
+-- is used to reach a spoke from mesh machines that are not one of its hubs.
+CREATE TABLE wireguard_hubs (
+    spoke_machine  hostname  NOT NULL,
+    hub_machine    hostname  NOT NULL,
+    interface      ifname    NOT NULL DEFAULT 'wg0',
+    PRIMARY KEY (spoke_machine, hub_machine, interface),
+    FOREIGN KEY (spoke_machine, interface) REFERENCES wireguard_interfaces(hostname, interface),
+    FOREIGN KEY (hub_machine, interface) REFERENCES wireguard_interfaces(hostname, interface),
+    CHECK (spoke_machine <> hub_machine)
+);
+SELECT periods.add_system_time_period('wireguard_hubs', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('wireguard_hubs');
+
 -- Note: you should use a different WireGuard port for each machine behind the same NAT.
 --
 -- WireGuard remembers just one endpoint per machine and if it gets a packet from IP:904
@@ -126,18 +287,14 @@ CREATE VIEW machines_view AS
         providers.email AS provider_email,
         provider_reference,
         coalesce(networks.networks, ARRAY['NONE']) AS networks,
-        wireguard_ipv4_address,
-        wireguard_ipv6_address,
-        wireguard_port,
-        wireguard_privkey,
-        wireguard_pubkey,
+        coalesce(interfaces.interfaces, ARRAY[]::varchar[]) AS wireguard_interfaces,
         ssh_port,
         ssh_user
     FROM machines
-    LEFT JOIN wireguard_interfaces ON machines.hostname    = wireguard_interfaces.hostname
     LEFT JOIN ssh_servers          ON machines.hostname    = ssh_servers.hostname
     LEFT JOIN providers            ON machines.provider_id = providers.id
-    LEFT JOIN (SELECT hostname, array_agg(network::varchar) AS networks FROM machine_addresses GROUP BY hostname) networks ON machines.hostname = networks.hostname;
+    LEFT JOIN (SELECT hostname, array_agg(network::varchar) AS networks FROM machine_addresses GROUP BY hostname) networks ON machines.hostname = networks.hostname
+    LEFT JOIN (SELECT hostname, array_agg(interface::varchar ORDER BY interface) AS interfaces FROM wireguard_interfaces GROUP BY hostname) interfaces ON machines.hostname = interfaces.hostname;
 
 CREATE VIEW providers_count AS
     SELECT count, provider_id, name, email FROM (
@@ -150,9 +307,15 @@ CREATE VIEW providers_count AS
 CREATE PROCEDURE remove_machine(kill_hostname varchar)
 LANGUAGE SQL
 AS $$
-    DELETE FROM wireguard_interfaces WHERE hostname = kill_hostname;
-    DELETE FROM ssh_servers          WHERE hostname = kill_hostname;
-    DELETE FROM machine_addresses    WHERE hostname = kill_hostname;
-    DELETE FROM wireguard_keepalives WHERE source_machine = kill_hostname OR target_machine = kill_hostname;
-    DELETE FROM machines             WHERE hostname = kill_hostname;
+    DELETE FROM wireguard_exit_routes WHERE source_machine = kill_hostname OR exit_machine = kill_hostname;
+    DELETE FROM wireguard_hubs        WHERE spoke_machine = kill_hostname OR hub_machine = kill_hostname;
+    DELETE FROM wireguard_interfaces  WHERE hostname = kill_hostname;
+    DELETE FROM ssh_servers           WHERE hostname = kill_hostname;
+    DELETE FROM machine_ssh_options   WHERE hostname = kill_hostname;
+    DELETE FROM ssh_bastions          WHERE hostname = kill_hostname OR bastion = kill_hostname;
+    DELETE FROM ssh_host_keys         WHERE hostname = kill_hostname;
+    DELETE FROM ssh_grants            WHERE hostname = kill_hostname;
+    DELETE FROM machine_addresses     WHERE hostname = kill_hostname;
+    DELETE FROM wireguard_keepalives  WHERE source_machine = kill_hostname OR target_machine = kill_hostname;
+    DELETE FROM machines              WHERE hostname = kill_hostname;
 $$;
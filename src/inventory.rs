@@ -0,0 +1,380 @@
+//! The JSON inventory document written by `export` and read by `import`
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{WireguardSettings, DEFAULT_WIREGUARD_INTERFACE};
+
+/// Incremented whenever a change to the document would break its consumers
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// Everything in the database except private keys and history.  Every list is sorted
+/// so that the same database always produces the same document.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Inventory {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub owners: Vec<String>,
+    #[serde(default)]
+    pub providers: Vec<Provider>,
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(default)]
+    pub network_links: Vec<NetworkLink>,
+    #[serde(default)]
+    pub wireguard_overlays: Vec<WireguardOverlay>,
+    #[serde(default)]
+    pub machines: Vec<Machine>,
+    #[serde(default)]
+    pub wireguard_keepalives: Vec<WireguardKeepalive>,
+    #[serde(default)]
+    pub wireguard_exit_routes: Vec<WireguardExitRoute>,
+    #[serde(default)]
+    pub wireguard_hubs: Vec<WireguardHub>,
+    #[serde(default)]
+    pub network_ssh_options: Vec<NetworkSshOption>,
+    #[serde(default)]
+    pub owner_ssh_options: Vec<OwnerSshOption>,
+    #[serde(default)]
+    pub ssh_bastions: Vec<SshBastion>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub ssh_grants: Vec<SshGrant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Provider {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NetworkLink {
+    pub name: String,
+    pub other_network: String,
+    pub priority: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireguardOverlay {
+    pub interface: String,
+    pub ipv4_start: Option<Ipv4Addr>,
+    pub ipv4_end: Option<Ipv4Addr>,
+    pub ipv6_start: Option<Ipv6Addr>,
+    pub ipv6_end: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub settings: WireguardSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Machine {
+    pub hostname: String,
+    /// DEFAULT_OWNER when missing
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub provider_id: Option<i32>,
+    #[serde(default)]
+    pub provider_reference: Option<String>,
+    /// DEFAULT_SSH_PORT when missing; the machine has no SSH server when both ssh_port and ssh_user are missing
+    #[serde(default)]
+    pub ssh_port: Option<i32>,
+    /// DEFAULT_SSH_USER when missing; the machine has no SSH server when both ssh_port and ssh_user are missing
+    #[serde(default)]
+    pub ssh_user: Option<String>,
+    #[serde(default)]
+    pub addresses: Vec<MachineAddress>,
+    #[serde(default)]
+    pub wireguard_interfaces: Vec<WireguardInterface>,
+    /// ssh_config option -> value
+    #[serde(default)]
+    pub ssh_options: BTreeMap<String, String>,
+    #[serde(default)]
+    pub ssh_host_keys: Vec<SshHostKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MachineAddress {
+    pub network: String,
+    pub address: IpAddr,
+    #[serde(default)]
+    pub ssh_port: Option<i32>,
+    #[serde(default)]
+    pub wireguard_port: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireguardInterface {
+    pub interface: String,
+    /// DEFAULT_WIREGUARD_PORT when missing
+    #[serde(default)]
+    pub port: Option<i32>,
+    /// Allocated from the interface's address pool when missing
+    #[serde(default)]
+    pub ipv4_address: Option<Ipv4Addr>,
+    /// Allocated from the interface's address pool when missing
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    /// Ignored by `import`, which always generates a new keypair
+    #[serde(default)]
+    pub pubkey: Option<String>,
+    #[serde(default)]
+    pub exit_node: bool,
+    #[serde(default = "default_role")]
+    pub role: String,
+    #[serde(default)]
+    pub settings: WireguardSettings,
+}
+
+fn default_role() -> String {
+    "mesh".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireguardKeepalive {
+    pub source_machine: String,
+    pub target_machine: String,
+    pub interval_sec: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireguardExitRoute {
+    pub source_machine: String,
+    pub exit_machine: String,
+    pub interface: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireguardHub {
+    pub spoke_machine: String,
+    pub hub_machine: String,
+    pub interface: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SshHostKey {
+    pub key_type: String,
+    pub public_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NetworkSshOption {
+    pub network: String,
+    pub option: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OwnerSshOption {
+    pub owner: String,
+    pub option: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SshBastion {
+    pub hostname: String,
+    pub bastion: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct User {
+    pub name: String,
+    #[serde(default)]
+    pub ssh_keys: Vec<UserSshKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UserSshKey {
+    pub key_type: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+/// Exactly one of `hostname` and `owner` is set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SshGrant {
+    pub user: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// The machine's ssh_user when missing
+    #[serde(default)]
+    pub login: Option<String>,
+}
+
+/// A row of a CSV file for `import`.  Rows with the same hostname describe the same machine
+/// and may leave the machine's columns empty after the first row.  A machine gets a WireGuard
+/// interface only from rows that set wireguard_interface or wireguard_port, and an SSH server
+/// only if some row sets ssh_port or ssh_user.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CsvRow {
+    hostname: String,
+    owner: Option<String>,
+    provider_id: Option<i32>,
+    provider_reference: Option<String>,
+    ssh_port: Option<i32>,
+    ssh_user: Option<String>,
+    wireguard_interface: Option<String>,
+    wireguard_port: Option<i32>,
+    network: Option<String>,
+    address: Option<IpAddr>,
+    address_ssh_port: Option<i32>,
+    address_wireguard_port: Option<i32>,
+}
+
+/// Set `field` to `value`, unless an earlier row already set it to something else
+fn merge_field<T: PartialEq + std::fmt::Debug>(hostname: &str, column: &str, field: &mut Option<T>, value: Option<T>) -> Result<()> {
+    match (&field, value) {
+        (_, None) => {},
+        (None, value) => *field = value,
+        (Some(existing), Some(value)) => {
+            ensure!(*existing == value, "Conflicting values {:?} and {:?} for column {:?} of machine {:?}", existing, value, column, hostname);
+        }
+    }
+    Ok(())
+}
+
+/// Read machines and their addresses from a CSV file with a header row
+pub(crate) fn from_csv(reader: impl Read) -> Result<Inventory> {
+    let mut machines: Vec<Machine> = vec![];
+    let mut reader = csv::Reader::from_reader(reader);
+    for (i, row) in reader.deserialize::<CsvRow>().enumerate() {
+        // Line 1 is the header
+        let row = row.with_context(|| format!("Could not parse CSV line {}", i + 2))?;
+        let hostname = row.hostname.clone();
+        let machine = match machines.iter().position(|m| m.hostname == hostname) {
+            Some(idx) => &mut machines[idx],
+            None => {
+                machines.push(Machine {
+                    hostname: hostname.clone(),
+                    owner: None,
+                    provider_id: None,
+                    provider_reference: None,
+                    ssh_port: None,
+                    ssh_user: None,
+                    addresses: vec![],
+                    wireguard_interfaces: vec![],
+                    ssh_options: BTreeMap::new(),
+                    ssh_host_keys: vec![],
+                });
+                machines.last_mut().unwrap()
+            }
+        };
+        merge_field(&hostname, "owner", &mut machine.owner, row.owner)?;
+        merge_field(&hostname, "provider_id", &mut machine.provider_id, row.provider_id)?;
+        merge_field(&hostname, "provider_reference", &mut machine.provider_reference, row.provider_reference)?;
+        merge_field(&hostname, "ssh_port", &mut machine.ssh_port, row.ssh_port)?;
+        merge_field(&hostname, "ssh_user", &mut machine.ssh_user, row.ssh_user)?;
+
+        let interface = match (row.wireguard_interface, row.wireguard_port) {
+            (Some(interface), _) => Some(interface),
+            (None, Some(_)) => Some(DEFAULT_WIREGUARD_INTERFACE.to_string()),
+            (None, None) => None,
+        };
+        if let Some(interface) = interface {
+            let wireguard_interface = match machine.wireguard_interfaces.iter().position(|w| w.interface == interface) {
+                Some(idx) => &mut machine.wireguard_interfaces[idx],
+                None => {
+                    machine.wireguard_interfaces.push(WireguardInterface {
+                        interface,
+                        port: None,
+                        ipv4_address: None,
+                        ipv6_address: None,
+                        pubkey: None,
+                        exit_node: false,
+                        role: default_role(),
+                        settings: WireguardSettings::default(),
+                    });
+                    machine.wireguard_interfaces.last_mut().unwrap()
+                }
+            };
+            merge_field(&hostname, "wireguard_port", &mut wireguard_interface.port, row.wireguard_port)?;
+        }
+
+        match (row.network, row.address) {
+            (Some(network), Some(address)) => {
+                machine.addresses.push(MachineAddress {
+                    network,
+                    address,
+                    ssh_port: row.address_ssh_port,
+                    wireguard_port: row.address_wireguard_port,
+                });
+            }
+            (None, None) => {
+                ensure!(row.address_ssh_port.is_none() && row.address_wireguard_port.is_none(),
+                        "CSV line {} has address ports but no address", i + 2);
+            }
+            _ => bail!("CSV line {} must have both a network and an address, or neither", i + 2),
+        }
+    }
+    Ok(Inventory {
+        schema_version: SCHEMA_VERSION,
+        machines,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::from_csv;
+
+    #[test]
+    fn test_from_csv() {
+        let csv = "\
+hostname,owner,provider_id,provider_reference,ssh_port,ssh_user,wireguard_interface,wireguard_port,network,address,address_ssh_port,address_wireguard_port
+a,me,1,vps-1,22,root,,51820,internet,192.0.2.1,22,51820
+a,,,,,,,,homelan,10.0.0.1,,
+b,,,,,,wg1,,,,,
+c,,,,,,,,internet,192.0.2.3,,
+";
+        let inventory = from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(inventory.machines.len(), 3);
+        let a = &inventory.machines[0];
+        assert_eq!(a.owner.as_deref(), Some("me"));
+        assert_eq!(a.provider_id, Some(1));
+        assert_eq!(a.addresses.len(), 2);
+        assert_eq!(a.addresses[1].address, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(a.wireguard_interfaces.len(), 1);
+        assert_eq!(a.wireguard_interfaces[0].interface, "wg0");
+        assert_eq!(a.wireguard_interfaces[0].port, Some(51820));
+        let b = &inventory.machines[1];
+        assert_eq!(b.owner, None);
+        assert!(b.addresses.is_empty());
+        assert_eq!(b.wireguard_interfaces[0].interface, "wg1");
+        let c = &inventory.machines[2];
+        assert_eq!(c.ssh_port, None);
+        assert!(c.wireguard_interfaces.is_empty());
+
+        let conflicting = "hostname,owner\na,me\na,you\n";
+        assert!(from_csv(conflicting.as_bytes()).is_err());
+        let missing_address = "hostname,network\na,internet\n";
+        assert!(from_csv(missing_address.as_bytes()).is_err());
+    }
+}
@@ -2,15 +2,18 @@
 
 mod wireguard;
 mod nix;
+mod inventory;
 mod table_cell;
 #[macro_use] mod macros;
 
 use std::iter;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io::Write;
-use std::fs::File;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::string::ToString;
 use std::convert::TryFrom;
@@ -21,8 +24,9 @@ use structopt::StructOpt;
 use natural_sort::HumanStr;
 use itertools::{Itertools, iproduct};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
-use nix::ToNix;
+use nix::{NixValue, ToNix};
 use table_cell::ToTableCell;
 
 fn import_env() -> Result<()> {
@@ -31,19 +35,256 @@ fn import_env() -> Result<()> {
         .with_context(|| format!("Unable to read configuration from {:?}", &path))
 }
 
+/// The WireGuard interface used when one is not specified
+const DEFAULT_WIREGUARD_INTERFACE: &str = "wg0";
+
 fn postgres_client() -> Result<Client> {
     let database_url = env_var("DATABASE_URL")?;
     Ok(Client::connect(&database_url, NoTls)?)
 }
 
+/// How a machine's WireGuard interface is connected to the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireguardRole {
+    /// Peers with every other mesh machine
+    Mesh,
+    /// Peers only with its hubs, which relay traffic for it
+    Spoke,
+}
+
+impl str::FromStr for WireguardRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mesh" => Ok(WireguardRole::Mesh),
+            "spoke" => Ok(WireguardRole::Spoke),
+            _ => bail!("Unknown WireGuard role {:?}", s),
+        }
+    }
+}
+
+impl WireguardRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WireguardRole::Mesh => "mesh",
+            WireguardRole::Spoke => "spoke",
+        }
+    }
+}
+
+/// Optional wg-quick [Interface] settings, from the machine's interface or the
+/// defaults for all interfaces with that name
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WireguardSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_up: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_up: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_down: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_down: Option<String>,
+}
+
+/// A command run when a WireGuard interface is brought up or down
+#[derive(Debug, Clone, Copy)]
+enum WireguardHook {
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+impl WireguardHook {
+    /// Key in a wg-quick [Interface] section
+    fn wg_quick_key(&self) -> &'static str {
+        match self {
+            WireguardHook::PreUp => "PreUp",
+            WireguardHook::PostUp => "PostUp",
+            WireguardHook::PreDown => "PreDown",
+            WireguardHook::PostDown => "PostDown",
+        }
+    }
+
+    /// Attribute in the wireguard_settings output by `nix-data`
+    fn nix_data_key(&self) -> &'static str {
+        match self {
+            WireguardHook::PreUp => "preUp",
+            WireguardHook::PostUp => "postUp",
+            WireguardHook::PreDown => "preDown",
+            WireguardHook::PostDown => "postDown",
+        }
+    }
+
+    /// Option of NixOS's networking.wireguard.interfaces.<name>
+    fn nixos_option(&self) -> &'static str {
+        match self {
+            WireguardHook::PreUp => "preSetup",
+            WireguardHook::PostUp => "postSetup",
+            WireguardHook::PreDown => "preShutdown",
+            WireguardHook::PostDown => "postShutdown",
+        }
+    }
+}
+
+impl WireguardSettings {
+    /// The hook commands that are set, in the order wg-quick runs them
+    fn hooks(&self) -> Vec<(WireguardHook, &str)> {
+        [
+            (WireguardHook::PreUp, &self.pre_up),
+            (WireguardHook::PostUp, &self.post_up),
+            (WireguardHook::PreDown, &self.pre_down),
+            (WireguardHook::PostDown, &self.post_down),
+        ]
+            .into_iter()
+            .filter_map(|(hook, command)| command.as_deref().map(|command| (hook, command)))
+            .collect()
+    }
+}
+
+/// A setting that can be changed with `wg-interface set` or `wg-overlay set`
+#[derive(Debug, Clone, Copy)]
+enum WireguardSetting {
+    Mtu,
+    Dns,
+    RouteTable,
+    Fwmark,
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+impl str::FromStr for WireguardSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mtu" => Ok(WireguardSetting::Mtu),
+            "dns" => Ok(WireguardSetting::Dns),
+            "table" => Ok(WireguardSetting::RouteTable),
+            "fwmark" => Ok(WireguardSetting::Fwmark),
+            "pre-up" => Ok(WireguardSetting::PreUp),
+            "post-up" => Ok(WireguardSetting::PostUp),
+            "pre-down" => Ok(WireguardSetting::PreDown),
+            "post-down" => Ok(WireguardSetting::PostDown),
+            _ => bail!("Unknown WireGuard setting {:?}, expected one of mtu, dns, table, fwmark, pre-up, post-up, pre-down, post-down", s),
+        }
+    }
+}
+
+impl WireguardSetting {
+    /// Column in wireguard_interfaces and wireguard_overlays
+    fn column(&self) -> &'static str {
+        match self {
+            WireguardSetting::Mtu => "mtu",
+            WireguardSetting::Dns => "dns",
+            WireguardSetting::RouteTable => "route_table",
+            WireguardSetting::Fwmark => "fwmark",
+            WireguardSetting::PreUp => "pre_up",
+            WireguardSetting::PostUp => "post_up",
+            WireguardSetting::PreDown => "pre_down",
+            WireguardSetting::PostDown => "post_down",
+        }
+    }
+
+    /// SQL expression converting text parameter `param` to the column's type
+    fn sql_value(&self, param: &str) -> String {
+        match self {
+            WireguardSetting::Mtu => format!("{param}::text::integer"),
+            WireguardSetting::Dns => format!("string_to_array({param}::text, ',')"),
+            WireguardSetting::Fwmark => format!("{param}::text::bigint"),
+            _ => format!("{param}::text"),
+        }
+    }
+
+    /// Check a value given on the command line and convert it to the text for `sql_value`
+    fn normalize_value(&self, value: &str) -> Result<String> {
+        match self {
+            WireguardSetting::Mtu => {
+                let mtu = value.parse::<u16>().with_context(|| anyhow!("Could not parse MTU {:?} as a u16", value))?;
+                ensure!(mtu >= 1280, "MTU must be at least 1280 for IPv6");
+                Ok(mtu.to_string())
+            },
+            WireguardSetting::Dns => {
+                let servers = value.split(',').map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+                ensure!(!servers.is_empty(), "DNS must list at least one server or search domain");
+                Ok(servers.join(","))
+            },
+            WireguardSetting::RouteTable => {
+                // Besides numbers, wg-quick accepts "main" and names from /etc/iproute2/rt_tables
+                ensure!(!value.is_empty() && value.len() <= 32 && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                        "Table must be off, auto, a routing table number, or a routing table name");
+                Ok(value.to_string())
+            },
+            WireguardSetting::Fwmark => {
+                let fwmark = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => value.parse::<u32>(),
+                }.with_context(|| anyhow!("Could not parse FwMark {:?} as a u32", value))?;
+                Ok(fwmark.to_string())
+            },
+            WireguardSetting::PreUp | WireguardSetting::PostUp | WireguardSetting::PreDown | WireguardSetting::PostDown => {
+                ensure!(!value.contains('\n'), "Hook commands cannot contain newlines");
+                Ok(value.to_string())
+            },
+        }
+    }
+}
+
+/// What a row in one of the *_ssh_options tables applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SshOptionScope {
+    Machine,
+    Network,
+    Owner,
+}
+
+impl SshOptionScope {
+    fn table(&self) -> &'static str {
+        match self {
+            SshOptionScope::Machine => "machine_ssh_options",
+            SshOptionScope::Network => "network_ssh_options",
+            SshOptionScope::Owner => "owner_ssh_options",
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            SshOptionScope::Machine => "hostname",
+            SshOptionScope::Network => "network",
+            SshOptionScope::Owner => "owner",
+        }
+    }
+}
+
+/// ssh_config keywords that `ssh-config` writes itself, or that would break up its Host blocks
+const RESERVED_SSH_OPTIONS: &[&str] = &["Host", "Match", "HostName", "Port", "User", "ProxyJump"];
+
+/// A machine, with the WireGuard fields of the interface it was loaded for
 #[derive(Debug)]
 pub struct Machine {
     pub hostname: String,
+    /// Names of all WireGuard interfaces on this machine
+    pub wireguard_interfaces: Vec<String>,
     pub wireguard_ipv4_address: Option<Ipv4Addr>,
     pub wireguard_ipv6_address: Option<Ipv6Addr>,
     pub wireguard_port: Option<i32>,
     pub wireguard_privkey: Option<String>,
     pub wireguard_pubkey: Option<String>,
+    pub exit_node: bool,
+    pub wireguard_role: WireguardRole,
+    pub wireguard_settings: WireguardSettings,
     pub ssh_port: Option<i32>,
     pub ssh_user: Option<String>,
     pub added_time: DateTime<Utc>,
@@ -63,6 +304,29 @@ pub struct MachineAddress {
     pub wireguard_port: Option<i32>,
 }
 
+/// The WireGuard fields of a machine that has the interface it was loaded for
+#[derive(Debug)]
+pub struct MachineWireguard<'a> {
+    pub ipv4_address: Ipv4Addr,
+    pub ipv6_address: Ipv6Addr,
+    pub port: i32,
+    pub privkey: &'a str,
+    pub pubkey: &'a str,
+}
+
+impl Machine {
+    /// Get the WireGuard fields, or an error if the machine does not have WireGuard
+    /// interface `interface`, which must be the interface it was loaded for
+    fn wireguard(&self, interface: &str) -> Result<MachineWireguard<'_>> {
+        match (self.wireguard_ipv4_address, self.wireguard_ipv6_address, self.wireguard_port, &self.wireguard_privkey, &self.wireguard_pubkey) {
+            (Some(ipv4_address), Some(ipv6_address), Some(port), Some(privkey), Some(pubkey)) => {
+                Ok(MachineWireguard { ipv4_address, ipv6_address, port, privkey, pubkey })
+            },
+            _ => bail!("Machine {:?} does not have WireGuard interface {:?}", self.hostname, interface),
+        }
+    }
+}
+
 /// A map of hostname -> Machine
 type MachinesMap = HashMap<String, Machine>;
 
@@ -72,6 +336,18 @@ type NetworkLinksPriorityMap = HashMap<(String, String), i32>;
 /// A map of (source_machine, target_machine) -> interval
 type WireguardKeepaliveIntervalMap = HashMap<(String, String), i32>;
 
+/// A map of source_machine -> exit_machine
+type WireguardExitRouteMap = HashMap<String, String>;
+
+/// A map of spoke_machine -> hub_machines, sorted by hostname
+type WireguardHubsMap = HashMap<String, Vec<String>>;
+
+/// A map of (scope, hostname/network/owner) -> [(option, value)], sorted by option
+type SshOptionsMap = HashMap<(SshOptionScope, String), Vec<(String, String)>>;
+
+/// A map of hostname -> bastion
+type SshBastionMap = HashMap<String, String>;
+
 fn get_network_links_priority_map(transaction: &mut Transaction) -> Result<NetworkLinksPriorityMap> {
     let map = transaction.query("SELECT name, other_network, priority FROM network_links", &[])?
         .into_iter()
@@ -88,6 +364,52 @@ fn get_wireguard_keepalive_map(transaction: &mut Transaction) -> Result<Wireguar
     Ok(map)
 }
 
+fn get_wireguard_exit_route_map(transaction: &mut Transaction, interface: &str) -> Result<WireguardExitRouteMap> {
+    let map = transaction.query("SELECT source_machine, exit_machine FROM wireguard_exit_routes WHERE interface = $1", &[&interface])?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect::<HashMap<_, _>>();
+    Ok(map)
+}
+
+fn get_wireguard_hubs_map(transaction: &mut Transaction, interface: &str) -> Result<WireguardHubsMap> {
+    let mut map: WireguardHubsMap = HashMap::new();
+    for row in transaction.query(
+        "SELECT spoke_machine, hub_machine FROM wireguard_hubs WHERE interface = $1 ORDER BY (spoke_machine, hub_machine)", &[&interface]
+    )? {
+        map.entry(row.get(0)).or_default().push(row.get(1));
+    }
+    Ok(map)
+}
+
+fn get_ssh_options_map(transaction: &mut Transaction) -> Result<SshOptionsMap> {
+    let mut map: SshOptionsMap = HashMap::new();
+    for scope in [SshOptionScope::Machine, SshOptionScope::Network, SshOptionScope::Owner] {
+        let query = format!("SELECT {}, option, value FROM {} ORDER BY lower(option)", scope.column(), scope.table());
+        for row in transaction.query(query.as_str(), &[])? {
+            map.entry((scope, row.get(0))).or_default().push((row.get(1), row.get(2)));
+        }
+    }
+    Ok(map)
+}
+
+/// Get a map of provider id -> provider name
+fn get_provider_names(transaction: &mut Transaction) -> Result<HashMap<i32, String>> {
+    let map = transaction.query("SELECT id, name FROM providers", &[])?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect::<HashMap<_, _>>();
+    Ok(map)
+}
+
+fn get_ssh_bastion_map(transaction: &mut Transaction) -> Result<SshBastionMap> {
+    let map = transaction.query("SELECT hostname, bastion FROM ssh_bastions", &[])?
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect::<HashMap<_, _>>();
+    Ok(map)
+}
+
 /// Get IPv4Addr from IpAddr or panic
 fn get_ipv4addr(ipaddr: IpAddr) -> Ipv4Addr {
     match ipaddr {
@@ -104,12 +426,25 @@ fn get_ipv6addr(ipaddr: IpAddr) -> Ipv6Addr {
     }
 }
 
-fn get_machines_with_addresses(transaction: &mut Transaction) -> Result<MachinesMap> {
+/// Get all machines, with WireGuard fields filled in for machines that have `wireguard_interface`
+fn get_machines_with_addresses(transaction: &mut Transaction, wireguard_interface: &str) -> Result<MachinesMap> {
     let mut machines = HashMap::new();
     for row in transaction.query(
-        "SELECT hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
-                ssh_port, ssh_user, added_time, owner, provider_id, provider_reference, networks
-         FROM machines_view", &[]
+        "SELECT machines_view.hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey,
+                ssh_port, ssh_user, added_time, owner, provider_id, provider_reference, networks,
+                coalesce(exit_node, false), coalesce(wireguard_role, 'mesh'), wireguard_interfaces,
+                coalesce(wireguard_interfaces.mtu,         wireguard_overlays.mtu),
+                coalesce(wireguard_interfaces.dns,         wireguard_overlays.dns),
+                coalesce(wireguard_interfaces.route_table, wireguard_overlays.route_table),
+                coalesce(wireguard_interfaces.fwmark,      wireguard_overlays.fwmark),
+                coalesce(wireguard_interfaces.pre_up,      wireguard_overlays.pre_up),
+                coalesce(wireguard_interfaces.post_up,     wireguard_overlays.post_up),
+                coalesce(wireguard_interfaces.pre_down,    wireguard_overlays.pre_down),
+                coalesce(wireguard_interfaces.post_down,   wireguard_overlays.post_down)
+         FROM machines_view
+         LEFT JOIN wireguard_interfaces ON machines_view.hostname = wireguard_interfaces.hostname AND wireguard_interfaces.interface = $1
+         LEFT JOIN wireguard_overlays   ON wireguard_interfaces.interface = wireguard_overlays.interface",
+        &[&wireguard_interface]
     )? {
         let wireguard_ipv4_address_ipaddr: Option<IpAddr> = row.get(1);
         let wireguard_ipv6_address_ipaddr: Option<IpAddr> = row.get(2);
@@ -117,11 +452,15 @@ fn get_machines_with_addresses(transaction: &mut Transaction) -> Result<Machines
         let wireguard_ipv6_address = wireguard_ipv6_address_ipaddr.map(get_ipv6addr);
         let machine = Machine {
             hostname: row.get(0),
+            wireguard_interfaces: row.get(15),
             wireguard_ipv4_address,
             wireguard_ipv6_address,
             wireguard_port: row.get(3),
             wireguard_privkey: row.get(4),
             wireguard_pubkey: row.get(5),
+            exit_node: row.get(13),
+            wireguard_role: row.get::<_, &str>(14).parse()?,
+            wireguard_settings: get_wireguard_settings(&row, 16),
             ssh_port: row.get(6),
             ssh_user: row.get(7),
             added_time: row.get(8),
@@ -217,6 +556,134 @@ fn remove_wireguard_keepalive(mut transaction: Transaction, source: &str, target
     Ok(())
 }
 
+fn list_wireguard_exit_routes(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["SOURCE", "EXIT", "INTERFACE"])?;
+    for row in transaction.query("SELECT source_machine, exit_machine, interface FROM wireguard_exit_routes ORDER BY source_machine", &[])? {
+        let source_machine: String = row.get(0);
+        let exit_machine: String = row.get(1);
+        let interface: String = row.get(2);
+        writeln!(tw, "{source_machine}\t{exit_machine}\t{interface}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn set_wireguard_exit_node(mut transaction: Transaction, hostname: &str, interface: &str, exit_node: bool) -> Result<()> {
+    if !exit_node {
+        let clients = transaction.query(
+            "SELECT source_machine FROM wireguard_exit_routes WHERE exit_machine = $1 AND interface = $2", &[&hostname, &interface]
+        )?
+            .into_iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>();
+        ensure!(clients.is_empty(), "Machine {:?} is still used as an exit node by {:?}", hostname, clients);
+    }
+    let num_updated = transaction.execute(
+        "UPDATE wireguard_interfaces SET exit_node = $3 WHERE hostname = $1 AND interface = $2",
+        &[&hostname, &interface, &exit_node],
+    )?;
+    ensure!(num_updated == 1, "Machine {:?} does not have WireGuard interface {:?}", hostname, interface);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Check that every machine with an exit route on `interface` has its exit node as a direct
+/// WireGuard peer, because the default route is only added to that peer
+fn check_wireguard_exit_routes(transaction: &mut Transaction, interface: &str) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    let hubs_map = get_wireguard_hubs_map(transaction, interface)?;
+    for (source, exit) in get_wireguard_exit_route_map(transaction, interface)?.iter().sorted() {
+        ensure!(is_wireguard_neighbor(&hubs_map, &machines_map[source], &machines_map[exit]),
+                "Machine {:?} can't use {:?} as its exit node on {:?} because they are not peers; \
+                 a spoke's exit node must be one of its hubs", source, exit, interface);
+    }
+    Ok(())
+}
+
+fn add_wireguard_exit_route(mut transaction: Transaction, source: &str, exit: &str, interface: &str) -> Result<()> {
+    let rows = transaction.query("SELECT exit_node FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2", &[&exit, &interface])?;
+    ensure!(!rows.is_empty(), "Machine {:?} does not have WireGuard interface {:?}", exit, interface);
+    let exit_node: bool = rows[0].get(0);
+    ensure!(exit_node, "Machine {:?} is not an exit node on {:?}", exit, interface);
+    transaction.execute(
+        "INSERT INTO wireguard_exit_routes (source_machine, exit_machine, interface)
+         VALUES ($1::varchar, $2::varchar, $3::varchar)",
+        &[&source, &exit, &interface],
+    )?;
+    check_wireguard_exit_routes(&mut transaction, interface)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn remove_wireguard_exit_route(mut transaction: Transaction, source: &str) -> Result<()> {
+    let num_deleted = transaction.execute(
+        "DELETE FROM wireguard_exit_routes WHERE source_machine = $1",
+        &[&source],
+    )?;
+    ensure!(num_deleted == 1, "Could not find exit route for {:?} in database", source);
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_wireguard_topology(transaction: &mut Transaction, interface: &str) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    let hubs_map = get_wireguard_hubs_map(transaction, interface)?;
+    let machines = get_sorted_machines(&machines_map);
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "ROLE", "HUBS"])?;
+    for machine in machines.into_iter().filter(|m| m.wireguard_pubkey.is_some()) {
+        let hubs = hubs_map.get(&machine.hostname).map(|hubs| hubs.join(" "));
+        write_table_cell(&mut tw, &machine.hostname)?;
+        write_table_cell(&mut tw, machine.wireguard_role.as_str().to_string())?;
+        write_table_cell(&mut tw, hubs)?;
+        tw.write_all(b"\n")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn set_wireguard_role(transaction: &mut Transaction, hostname: &str, interface: &str, role: WireguardRole) -> Result<()> {
+    let num_updated = transaction.execute(
+        "UPDATE wireguard_interfaces SET wireguard_role = $3 WHERE hostname = $1 AND interface = $2",
+        &[&hostname, &interface, &role.as_str()],
+    )?;
+    ensure!(num_updated == 1, "Machine {:?} does not have WireGuard interface {:?}", hostname, interface);
+    transaction.execute("DELETE FROM wireguard_hubs WHERE spoke_machine = $1 AND interface = $2", &[&hostname, &interface])?;
+    Ok(())
+}
+
+fn set_wireguard_mesh(mut transaction: Transaction, hostname: &str, interface: &str) -> Result<()> {
+    set_wireguard_role(&mut transaction, hostname, interface, WireguardRole::Mesh)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn set_wireguard_spoke(mut transaction: Transaction, hostname: &str, interface: &str, hubs: &[String]) -> Result<()> {
+    let spokes = transaction.query(
+        "SELECT spoke_machine FROM wireguard_hubs WHERE hub_machine = $1 AND interface = $2", &[&hostname, &interface]
+    )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    ensure!(spokes.is_empty(), "Machine {:?} is a hub for {:?} and cannot be a spoke", hostname, spokes);
+    for hub in hubs {
+        let rows = transaction.query("SELECT wireguard_role FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2", &[hub, &interface])?;
+        ensure!(!rows.is_empty(), "Hub {:?} does not have WireGuard interface {:?}", hub, interface);
+        let role: WireguardRole = rows[0].get::<_, &str>(0).parse()?;
+        ensure!(role == WireguardRole::Mesh, "Hub {:?} is not a mesh machine", hub);
+    }
+    set_wireguard_role(&mut transaction, hostname, interface, WireguardRole::Spoke)?;
+    for hub in hubs {
+        transaction.execute(
+            "INSERT INTO wireguard_hubs (spoke_machine, hub_machine, interface)
+             VALUES ($1::varchar, $2::varchar, $3::varchar)",
+            &[&hostname, hub, &interface],
+        )?;
+    }
+    check_wireguard_exit_routes(&mut transaction, interface)?;
+    transaction.commit()?;
+    Ok(())
+}
+
 fn add_address(
     mut transaction: Transaction,
     hostname: &str,
@@ -313,7 +780,7 @@ fn write_table_cell<T: ToTableCell>(tw: &mut TabWriter<Vec<u8>>, value: T) -> st
 }
 
 fn list_machines(transaction: &mut Transaction) -> Result<()> {
-    let machines_map = get_machines_with_addresses(transaction)?;
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
     let machines = get_sorted_machines(&machines_map);
     let mut tw = TabWriter::new(vec![]);
     let columns = vec!["HOSTNAME", "WG IPV4", "WG IPV6", "OWNER", "PROV", "REFERENCE", "ADDRESSES"];
@@ -333,121 +800,936 @@ fn list_machines(transaction: &mut Transaction) -> Result<()> {
     print_tabwriter(tw)
 }
 
-fn format_nix_address(address: &MachineAddress) -> String {
-    format!("{} = {{ ip = {}; ssh_port = {}; wireguard_port = {}; }}; ",
-            address.network,
-            address.address.to_nix(),
-            address.ssh_port.to_nix(),
-            address.wireguard_port.to_nix()
-    )
+fn format_nix_address(address: &MachineAddress) -> NixValue {
+    NixValue::attrs([
+        ("ip", address.address.to_nix()),
+        ("ssh_port", address.ssh_port.to_nix()),
+        ("wireguard_port", address.wireguard_port.to_nix()),
+    ])
+}
+
+/// Format the WireGuard settings that are set as a Nix attrset, using NixOS option names
+fn format_nix_wireguard_settings(settings: &WireguardSettings) -> NixValue {
+    let mut attrs = vec![];
+    if let Some(mtu) = settings.mtu {
+        attrs.push(("mtu", mtu.to_nix()));
+    }
+    if !settings.dns.is_empty() {
+        attrs.push(("dns", settings.dns.to_nix()));
+    }
+    if let Some(route_table) = &settings.route_table {
+        attrs.push(("table", route_table.to_nix()));
+    }
+    if let Some(fwmark) = settings.fwmark {
+        attrs.push(("fwMark", format!("{fwmark:#x}").to_nix()));
+    }
+    for (hook, command) in settings.hooks() {
+        attrs.push((hook.nix_data_key(), command.to_nix()));
+    }
+    NixValue::attrs(attrs)
 }
 
 fn nix_data(transaction: &mut Transaction) -> Result<()> {
-    let machines_map = get_machines_with_addresses(transaction)?;
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
+    let exit_route_map = get_wireguard_exit_route_map(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
     let machines = get_sorted_machines(&machines_map);
 
-    println!("{{");
-    let mut tw = TabWriter::new(vec![]).padding(1);
+    // A map of exit_machine -> the machines routing all of their traffic through it, which
+    // will need IP forwarding and NAT for their WireGuard addresses, sorted by hostname
+    let mut exit_clients_map: HashMap<&str, Vec<String>> = HashMap::new();
+    for machine in &machines {
+        if let Some(exit) = exit_route_map.get(&machine.hostname) {
+            exit_clients_map.entry(exit.as_str()).or_default().push(machine.hostname.clone());
+        }
+    }
+
+    let mut machines_attrs = vec![];
     for machine in machines.into_iter() {
-        writeln!(tw, "  {}\t= {{ owner = {};\twireguard_ipv4_address = {};\twireguard_ipv6_address = {};\twireguard_port = {};\tssh_port = {};\tprovider_id = {};\tprovider_reference = {};\taddresses = {{ {}}}; }};",
-                 machine.hostname,
-                 machine.owner.to_nix(),
-                 &machine.wireguard_ipv4_address.to_nix(),
-                 &machine.wireguard_ipv6_address.to_nix(),
-                 machine.wireguard_port.to_nix(),
-                 machine.ssh_port.to_nix(),
-                 &machine.provider_id.to_nix(),
-                 &machine.provider_reference.to_nix(),
-                 machine.addresses.iter().map(format_nix_address).join("")
-        )?;
+        let exit_clients = exit_clients_map.remove(machine.hostname.as_str()).unwrap_or_default();
+        let addresses = machine.addresses
+            .iter()
+            .map(|address| (address.network.clone(), format_nix_address(address)));
+        machines_attrs.push((machine.hostname.clone(), NixValue::attrs([
+            ("owner", machine.owner.to_nix()),
+            ("wireguard_ipv4_address", machine.wireguard_ipv4_address.to_nix()),
+            ("wireguard_ipv6_address", machine.wireguard_ipv6_address.to_nix()),
+            ("wireguard_port", machine.wireguard_port.to_nix()),
+            ("ssh_port", machine.ssh_port.to_nix()),
+            ("provider_id", machine.provider_id.to_nix()),
+            ("provider_reference", machine.provider_reference.to_nix()),
+            ("exit_node", machine.exit_node.to_nix()),
+            ("exit_clients", exit_clients.to_nix()),
+            ("wireguard_interfaces", machine.wireguard_interfaces.to_nix()),
+            ("wireguard_settings", format_nix_wireguard_settings(&machine.wireguard_settings)),
+            ("addresses", NixValue::attrs(addresses)),
+        ])));
     }
-    print_tabwriter(tw)?;
-    println!("}}");
+    println!("{}", NixValue::Attrs(machines_attrs));
     Ok(())
 }
 
-fn print_wireguard_privkey(transaction: &mut Transaction, hostname: &str) -> Result<()> {
-    let rows = transaction.query("SELECT hostname, wireguard_privkey FROM machines_view WHERE hostname = $1", &[&hostname])?;
-    ensure!(!rows.is_empty(), "Could not find machine {:?} in database", hostname);
-    let row = &rows[0];
-    let privkey: Option<&str> = row.get(1);
-    ensure!(privkey.is_some(), "Machine {:?} does not have WireGuard IP", hostname);
-    println!("{}", privkey.unwrap());
-    Ok(())
+/// Read the WireGuard settings stored in columns `first..first + 8`, in the order of `WireguardSetting`
+fn get_wireguard_settings(row: &postgres::Row, first: usize) -> WireguardSettings {
+    WireguardSettings {
+        mtu: row.get(first),
+        dns: row.get::<_, Option<Vec<String>>>(first + 1).unwrap_or_default(),
+        route_table: row.get(first + 2),
+        fwmark: row.get(first + 3),
+        pre_up: row.get(first + 4),
+        post_up: row.get(first + 5),
+        pre_down: row.get(first + 6),
+        post_down: row.get(first + 7),
+    }
 }
 
-fn get_existing_wireguard_ipv4_addresses(transaction: &mut Transaction) -> Result<impl Iterator<Item=Ipv4Addr>> {
-    let iter = transaction.query("SELECT wireguard_ipv4_address FROM wireguard_interfaces", &[])?
+fn get_inventory(transaction: &mut Transaction) -> Result<inventory::Inventory> {
+    let owners = transaction.query("SELECT owner FROM owners ORDER BY owner", &[])?
         .into_iter()
-        .filter_map(|row| {
-            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
-            wireguard_ipaddr.map(get_ipv4addr)
+        .map(|row| row.get(0))
+        .collect();
+    let providers = transaction.query("SELECT id, name, email FROM providers ORDER BY id", &[])?
+        .into_iter()
+        .map(|row| inventory::Provider { id: row.get(0), name: row.get(1), email: row.get(2) })
+        .collect();
+    let networks = transaction.query("SELECT name FROM networks ORDER BY name", &[])?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect();
+    let network_links = transaction.query("SELECT name, other_network, priority FROM network_links ORDER BY name, other_network", &[])?
+        .into_iter()
+        .map(|row| inventory::NetworkLink { name: row.get(0), other_network: row.get(1), priority: row.get(2) })
+        .collect();
+    let wireguard_overlays = transaction.query(
+        "SELECT interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end,
+                mtu, dns, route_table, fwmark, pre_up, post_up, pre_down, post_down
+         FROM wireguard_overlays ORDER BY interface", &[]
+    )?
+        .into_iter()
+        .map(|row| inventory::WireguardOverlay {
+            interface: row.get(0),
+            ipv4_start: row.get::<_, Option<IpAddr>>(1).map(get_ipv4addr),
+            ipv4_end: row.get::<_, Option<IpAddr>>(2).map(get_ipv4addr),
+            ipv6_start: row.get::<_, Option<IpAddr>>(3).map(get_ipv6addr),
+            ipv6_end: row.get::<_, Option<IpAddr>>(4).map(get_ipv6addr),
+            settings: get_wireguard_settings(&row, 5),
+        })
+        .collect();
+
+    let mut addresses_map: HashMap<String, Vec<inventory::MachineAddress>> = HashMap::new();
+    for row in transaction.query(
+        "SELECT hostname, network, address, ssh_port, wireguard_port FROM machine_addresses ORDER BY hostname, network, address", &[]
+    )? {
+        addresses_map.entry(row.get(0)).or_default().push(inventory::MachineAddress {
+            network: row.get(1),
+            address: row.get(2),
+            ssh_port: row.get(3),
+            wireguard_port: row.get(4),
         });
-    Ok(iter)
-}
+    }
+    let mut wireguard_interfaces_map: HashMap<String, Vec<inventory::WireguardInterface>> = HashMap::new();
+    for row in transaction.query(
+        "SELECT hostname, interface, wireguard_port, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_pubkey, exit_node, wireguard_role,
+                mtu, dns, route_table, fwmark, pre_up, post_up, pre_down, post_down
+         FROM wireguard_interfaces ORDER BY hostname, interface", &[]
+    )? {
+        wireguard_interfaces_map.entry(row.get(0)).or_default().push(inventory::WireguardInterface {
+            interface: row.get(1),
+            port: Some(row.get(2)),
+            ipv4_address: Some(get_ipv4addr(row.get(3))),
+            ipv6_address: Some(get_ipv6addr(row.get(4))),
+            pubkey: Some(row.get(5)),
+            exit_node: row.get(6),
+            role: row.get(7),
+            settings: get_wireguard_settings(&row, 8),
+        });
+    }
+    let mut ssh_options_map: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+    for row in transaction.query("SELECT hostname, option, value FROM machine_ssh_options", &[])? {
+        ssh_options_map.entry(row.get(0)).or_default().insert(row.get(1), row.get(2));
+    }
+    let mut ssh_host_keys_map: HashMap<String, Vec<inventory::SshHostKey>> = HashMap::new();
+    for row in transaction.query("SELECT hostname, key_type, public_key FROM ssh_host_keys ORDER BY hostname, key_type", &[])? {
+        ssh_host_keys_map.entry(row.get(0)).or_default().push(inventory::SshHostKey { key_type: row.get(1), public_key: row.get(2) });
+    }
 
-fn get_existing_wireguard_ipv6_addresses(transaction: &mut Transaction) -> Result<impl Iterator<Item=Ipv6Addr>> {
-    let iter = transaction.query("SELECT wireguard_ipv6_address FROM wireguard_interfaces", &[])?
+    let machines = transaction.query(
+        "SELECT machines.hostname, owner, provider_id, provider_reference, ssh_port, ssh_user
+         FROM machines
+         LEFT JOIN ssh_servers ON ssh_servers.hostname = machines.hostname
+         ORDER BY machines.hostname", &[]
+    )?
         .into_iter()
-        .filter_map(|row| {
-            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
-            wireguard_ipaddr.map(get_ipv6addr)
-        });
-    Ok(iter)
+        .map(|row| {
+            let hostname: String = row.get(0);
+            inventory::Machine {
+                owner: Some(row.get(1)),
+                provider_id: row.get(2),
+                provider_reference: row.get(3),
+                ssh_port: row.get(4),
+                ssh_user: row.get(5),
+                addresses: addresses_map.remove(&hostname).unwrap_or_default(),
+                wireguard_interfaces: wireguard_interfaces_map.remove(&hostname).unwrap_or_default(),
+                ssh_options: ssh_options_map.remove(&hostname).unwrap_or_default(),
+                ssh_host_keys: ssh_host_keys_map.remove(&hostname).unwrap_or_default(),
+                hostname,
+            }
+        })
+        .collect();
+
+    let wireguard_keepalives = transaction.query("SELECT source_machine, target_machine, interval_sec FROM wireguard_keepalives ORDER BY source_machine, target_machine", &[])?
+        .into_iter()
+        .map(|row| inventory::WireguardKeepalive { source_machine: row.get(0), target_machine: row.get(1), interval_sec: row.get(2) })
+        .collect();
+    let wireguard_exit_routes = transaction.query("SELECT source_machine, exit_machine, interface FROM wireguard_exit_routes ORDER BY source_machine", &[])?
+        .into_iter()
+        .map(|row| inventory::WireguardExitRoute { source_machine: row.get(0), exit_machine: row.get(1), interface: row.get(2) })
+        .collect();
+    let wireguard_hubs = transaction.query("SELECT spoke_machine, hub_machine, interface FROM wireguard_hubs ORDER BY spoke_machine, interface, hub_machine", &[])?
+        .into_iter()
+        .map(|row| inventory::WireguardHub { spoke_machine: row.get(0), hub_machine: row.get(1), interface: row.get(2) })
+        .collect();
+    let network_ssh_options = transaction.query("SELECT network, option, value FROM network_ssh_options ORDER BY network, option", &[])?
+        .into_iter()
+        .map(|row| inventory::NetworkSshOption { network: row.get(0), option: row.get(1), value: row.get(2) })
+        .collect();
+    let owner_ssh_options = transaction.query("SELECT owner, option, value FROM owner_ssh_options ORDER BY owner, option", &[])?
+        .into_iter()
+        .map(|row| inventory::OwnerSshOption { owner: row.get(0), option: row.get(1), value: row.get(2) })
+        .collect();
+    let ssh_bastions = transaction.query("SELECT hostname, bastion FROM ssh_bastions ORDER BY hostname", &[])?
+        .into_iter()
+        .map(|row| inventory::SshBastion { hostname: row.get(0), bastion: row.get(1) })
+        .collect();
+    let mut user_ssh_keys_map: HashMap<String, Vec<inventory::UserSshKey>> = HashMap::new();
+    for row in transaction.query("SELECT name, key_type, public_key, comment FROM user_ssh_keys ORDER BY name, key_type, public_key", &[])? {
+        user_ssh_keys_map.entry(row.get(0)).or_default().push(inventory::UserSshKey { key_type: row.get(1), public_key: row.get(2), comment: row.get(3) });
+    }
+    let users = transaction.query("SELECT name FROM users ORDER BY name", &[])?
+        .into_iter()
+        .map(|row| {
+            let name: String = row.get(0);
+            inventory::User { ssh_keys: user_ssh_keys_map.remove(&name).unwrap_or_default(), name }
+        })
+        .collect();
+    let ssh_grants = transaction.query("SELECT name, hostname, owner, login FROM ssh_grants ORDER BY name, hostname, owner, login", &[])?
+        .into_iter()
+        .map(|row| inventory::SshGrant { user: row.get(0), hostname: row.get(1), owner: row.get(2), login: row.get(3) })
+        .collect();
+
+    Ok(inventory::Inventory {
+        schema_version: inventory::SCHEMA_VERSION,
+        owners,
+        providers,
+        networks,
+        network_links,
+        wireguard_overlays,
+        machines,
+        wireguard_keepalives,
+        wireguard_exit_routes,
+        wireguard_hubs,
+        network_ssh_options,
+        owner_ssh_options,
+        ssh_bastions,
+        users,
+        ssh_grants,
+    })
 }
 
-#[allow(clippy::trivially_copy_pass_by_ref)]
-fn increment_ipv4_address(ip: &Ipv4Addr) -> Option<Ipv4Addr> {
-    let mut octets = ip.octets();
-    if octets == [255, 255, 255, 255] {
-        return None;
+/// Print the whole inventory, without private keys, as JSON
+fn export(transaction: &mut Transaction) -> Result<()> {
+    let inventory = get_inventory(transaction)?;
+    println!("{}", serde_json::to_string_pretty(&inventory)?);
+    Ok(())
+}
+
+/// Convert a port from an inventory document to the u16 that `add` takes
+fn inventory_port(port: Option<i32>, what: &str) -> Result<Option<u16>> {
+    port.map(|port| u16::try_from(port).with_context(|| anyhow!("Invalid {} {}", what, port)))
+        .transpose()
+}
+
+/// Insert everything in `inventory` that is not already in the database, returning a
+/// description of each change.  Records that already exist are left unchanged.
+fn import_inventory(transaction: &mut Transaction, inventory: &inventory::Inventory) -> Result<Vec<String>> {
+    ensure!(inventory.schema_version == inventory::SCHEMA_VERSION,
+            "Unsupported schema_version {}, expected {}", inventory.schema_version, inventory::SCHEMA_VERSION);
+    let mut plan = vec![];
+
+    for owner in &inventory.owners {
+        if transaction.execute("INSERT INTO owners (owner) VALUES ($1::varchar) ON CONFLICT (owner) DO NOTHING", &[owner])? == 1 {
+            plan.push(format!("add owner {owner}"));
+        }
     }
-    for i in (0..4).rev() {
-        if octets[i] < 255 {
-            octets[i] += 1;
-            break;
-        } else {
-            octets[i] = 0;
+    for provider in &inventory.providers {
+        let inventory::Provider { id, name, email } = provider;
+        if transaction.execute(
+            "INSERT INTO providers (id, name, email) VALUES ($1::integer, $2::varchar, $3::varchar) ON CONFLICT (id) DO NOTHING",
+            &[id, name, email]
+        )? == 1 {
+            plan.push(format!("add provider {id} {name} {email}"));
         }
     }
-    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
-}
-
-fn increment_ipv6_address(ip: &Ipv6Addr) -> Option<Ipv6Addr> {
-    let mut segments = ip.segments();
-    if segments == [0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff] {
-        return None;
+    if !inventory.providers.is_empty() {
+        // Providers were inserted with explicit IDs, so `provider add` must not reuse them
+        transaction.execute("SELECT setval(pg_get_serial_sequence('providers', 'id'), max(id)) FROM providers", &[])?;
     }
-    for i in (0..8).rev() {
-        if segments[i] < 0xffff {
-            segments[i] += 1;
-            break;
-        } else {
-            segments[i] = 0;
+    for network in &inventory.networks {
+        if transaction.execute("INSERT INTO networks (name) VALUES ($1::varchar) ON CONFLICT (name) DO NOTHING", &[network])? == 1 {
+            plan.push(format!("add network {network}"));
         }
     }
-    Some(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]))
-}
-
-fn get_unused_wireguard_ipv4_address(transaction: &mut Transaction, start_ip: Ipv4Addr, end_ip: Ipv4Addr) -> Result<Option<Ipv4Addr>> {
-    let existing = get_existing_wireguard_ipv4_addresses(transaction)?.collect::<HashSet<Ipv4Addr>>();
-    let ip_iter = iter::successors(Some(start_ip), increment_ipv4_address);
-    for proposed_ip in ip_iter {
-        if !existing.contains(&proposed_ip) {
-            return Ok(Some(proposed_ip));
+    for link in &inventory.network_links {
+        let inventory::NetworkLink { name, other_network, priority } = link;
+        if transaction.execute(
+            "INSERT INTO network_links (name, other_network, priority) VALUES ($1::varchar, $2::varchar, $3::integer)
+             ON CONFLICT (name, other_network) DO NOTHING",
+            &[name, other_network, priority]
+        )? == 1 {
+            plan.push(format!("add network link {name} {other_network} priority {priority}"));
         }
-        if proposed_ip == end_ip {
-            break;
+    }
+    for overlay in &inventory.wireguard_overlays {
+        let inventory::WireguardOverlay { interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end, settings } = overlay;
+        let dns = (!settings.dns.is_empty()).then_some(&settings.dns);
+        if transaction.execute(
+            "INSERT INTO wireguard_overlays (interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end,
+                                             mtu, dns, route_table, fwmark, pre_up, post_up, pre_down, post_down)
+             VALUES ($1::varchar, $2::inet, $3::inet, $4::inet, $5::inet,
+                     $6::integer, $7::text[], $8::varchar, $9::bigint, $10::text, $11::text, $12::text, $13::text)
+             ON CONFLICT (interface) DO NOTHING",
+            &[interface, &ipv4_start.map(IpAddr::V4), &ipv4_end.map(IpAddr::V4), &ipv6_start.map(IpAddr::V6), &ipv6_end.map(IpAddr::V6),
+              &settings.mtu, &dns, &settings.route_table, &settings.fwmark, &settings.pre_up, &settings.post_up, &settings.pre_down, &settings.post_down]
+        )? == 1 {
+            plan.push(format!("add WireGuard overlay {interface}"));
         }
     }
-    Ok(None)
-}
 
-fn get_unused_wireguard_ipv6_address(transaction: &mut Transaction, start_ip: Ipv6Addr, end_ip: Ipv6Addr) -> Result<Option<Ipv6Addr>> {
-    let existing = get_existing_wireguard_ipv6_addresses(transaction)?.collect::<HashSet<Ipv6Addr>>();
-    let ip_iter = iter::successors(Some(start_ip), increment_ipv6_address);
-    for proposed_ip in ip_iter {
-        if !existing.contains(&proposed_ip) {
+    for machine in &inventory.machines {
+        let hostname = &machine.hostname;
+        let exists = !transaction.query("SELECT 1 FROM machines WHERE hostname = $1", &[hostname])?.is_empty();
+        if !exists {
+            insert_machine(
+                transaction,
+                hostname,
+                machine.owner.clone(),
+                machine.provider_id,
+                machine.provider_reference.clone(),
+            ).with_context(|| anyhow!("Could not add machine {:?}", hostname))?;
+            plan.push(format!("add machine {hostname}"));
+            // Like `add --no-ssh`, a machine without SSH fields gets no SSH server
+            if machine.ssh_port.is_some() || machine.ssh_user.is_some() {
+                insert_ssh_server(
+                    transaction,
+                    hostname,
+                    inventory_port(machine.ssh_port, "SSH port")?,
+                    machine.ssh_user.clone(),
+                ).with_context(|| anyhow!("Could not add SSH server to machine {:?}", hostname))?;
+                plan.push(format!("add SSH server {hostname}"));
+            }
+        }
+        for wireguard_interface in &machine.wireguard_interfaces {
+            let interface = &wireguard_interface.interface;
+            let exists = !transaction.query(
+                "SELECT 1 FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2", &[hostname, interface]
+            )?.is_empty();
+            if exists {
+                continue;
+            }
+            let role: WireguardRole = wireguard_interface.role.parse()?;
+            let (ipv4, ipv6, port) = insert_wireguard_interface(
+                transaction,
+                hostname,
+                interface,
+                wireguard_interface.ipv4_address,
+                wireguard_interface.ipv6_address,
+                inventory_port(wireguard_interface.port, "WireGuard port")?,
+            ).with_context(|| anyhow!("Could not add WireGuard interface {:?} to machine {:?}", interface, hostname))?;
+            let settings = &wireguard_interface.settings;
+            let dns = (!settings.dns.is_empty()).then_some(&settings.dns);
+            transaction.execute(
+                "UPDATE wireguard_interfaces
+                 SET exit_node = $3, wireguard_role = $4::varchar, mtu = $5::integer, dns = $6::text[], route_table = $7::varchar,
+                     fwmark = $8::bigint, pre_up = $9::text, post_up = $10::text, pre_down = $11::text, post_down = $12::text
+                 WHERE hostname = $1 AND interface = $2",
+                &[hostname, interface, &wireguard_interface.exit_node, &role.as_str(), &settings.mtu, &dns, &settings.route_table,
+                  &settings.fwmark, &settings.pre_up, &settings.post_up, &settings.pre_down, &settings.post_down]
+            )?;
+            plan.push(format!("add WireGuard interface {hostname} {interface} {ipv4} {ipv6} port {port}, with a new keypair"));
+        }
+        for address in &machine.addresses {
+            let inventory::MachineAddress { network, address, ssh_port, wireguard_port } = address;
+            if transaction.execute(
+                "INSERT INTO machine_addresses (hostname, network, address, ssh_port, wireguard_port)
+                 VALUES ($1::varchar, $2::varchar, $3::inet, $4::integer, $5::integer)
+                 ON CONFLICT (hostname, network, address) DO NOTHING",
+                &[hostname, network, address, ssh_port, wireguard_port]
+            ).with_context(|| anyhow!("Could not add address {} to machine {:?}", address, hostname))? == 1 {
+                plan.push(format!("add address {hostname} {network} {address}"));
+            }
+        }
+    }
+
+    for keepalive in &inventory.wireguard_keepalives {
+        let inventory::WireguardKeepalive { source_machine, target_machine, interval_sec } = keepalive;
+        if transaction.execute(
+            "INSERT INTO wireguard_keepalives (source_machine, target_machine, interval_sec)
+             VALUES ($1::varchar, $2::varchar, $3::integer)
+             ON CONFLICT (source_machine, target_machine) DO NOTHING",
+            &[source_machine, target_machine, interval_sec]
+        )? == 1 {
+            plan.push(format!("add WireGuard keepalive {source_machine} {target_machine} {interval_sec}"));
+        }
+    }
+    for exit_route in &inventory.wireguard_exit_routes {
+        let inventory::WireguardExitRoute { source_machine, exit_machine, interface } = exit_route;
+        let rows = transaction.query("SELECT exit_node FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2", &[exit_machine, interface])?;
+        ensure!(!rows.is_empty(), "Machine {:?} does not have WireGuard interface {:?}", exit_machine, interface);
+        ensure!(rows[0].get::<_, bool>(0), "Machine {:?} is not an exit node on {:?}", exit_machine, interface);
+        if transaction.execute(
+            "INSERT INTO wireguard_exit_routes (source_machine, exit_machine, interface)
+             VALUES ($1::varchar, $2::varchar, $3::varchar)
+             ON CONFLICT (source_machine) DO NOTHING",
+            &[source_machine, exit_machine, interface]
+        )? == 1 {
+            plan.push(format!("add WireGuard exit route {source_machine} {exit_machine} {interface}"));
+        }
+    }
+    for hub in &inventory.wireguard_hubs {
+        let inventory::WireguardHub { spoke_machine, hub_machine, interface } = hub;
+        for (hostname, expected_role) in [(spoke_machine, WireguardRole::Spoke), (hub_machine, WireguardRole::Mesh)] {
+            let rows = transaction.query("SELECT wireguard_role FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2", &[hostname, interface])?;
+            ensure!(!rows.is_empty(), "Machine {:?} does not have WireGuard interface {:?}", hostname, interface);
+            let role: WireguardRole = rows[0].get::<_, &str>(0).parse()?;
+            ensure!(role == expected_role, "Machine {:?} must be a {} machine on {:?}", hostname, expected_role.as_str(), interface);
+        }
+        if transaction.execute(
+            "INSERT INTO wireguard_hubs (spoke_machine, hub_machine, interface)
+             VALUES ($1::varchar, $2::varchar, $3::varchar)
+             ON CONFLICT (spoke_machine, hub_machine, interface) DO NOTHING",
+            &[spoke_machine, hub_machine, interface]
+        )? == 1 {
+            plan.push(format!("add WireGuard hub {hub_machine} for {spoke_machine} {interface}"));
+        }
+    }
+
+    for machine in &inventory.machines {
+        let hostname = &machine.hostname;
+        for (option, value) in &machine.ssh_options {
+            if insert_ssh_option(transaction, SshOptionScope::Machine, hostname, option, value)? {
+                plan.push(format!("add SSH option for machine {hostname}: {option} {value}"));
+            }
+        }
+        for host_key in &machine.ssh_host_keys {
+            let inventory::SshHostKey { key_type, public_key } = host_key;
+            check_ssh_public_key(key_type, public_key)?;
+            if transaction.execute(
+                "INSERT INTO ssh_host_keys (hostname, key_type, public_key) VALUES ($1::varchar, $2::varchar, $3::text)
+                 ON CONFLICT (hostname, key_type) DO NOTHING",
+                &[hostname, key_type, public_key]
+            )? == 1 {
+                plan.push(format!("add SSH host key {hostname} {key_type}"));
+            }
+        }
+    }
+    for network_option in &inventory.network_ssh_options {
+        let inventory::NetworkSshOption { network, option, value } = network_option;
+        if insert_ssh_option(transaction, SshOptionScope::Network, network, option, value)? {
+            plan.push(format!("add SSH option for network {network}: {option} {value}"));
+        }
+    }
+    for owner_option in &inventory.owner_ssh_options {
+        let inventory::OwnerSshOption { owner, option, value } = owner_option;
+        if insert_ssh_option(transaction, SshOptionScope::Owner, owner, option, value)? {
+            plan.push(format!("add SSH option for owner {owner}: {option} {value}"));
+        }
+    }
+    for ssh_bastion in &inventory.ssh_bastions {
+        let inventory::SshBastion { hostname, bastion } = ssh_bastion;
+        let has_ssh_server = transaction.query_opt("SELECT 1 FROM ssh_servers WHERE hostname = $1", &[bastion])?.is_some();
+        ensure!(has_ssh_server, "Machine {:?} does not have an SSH server", bastion);
+        if transaction.execute(
+            "INSERT INTO ssh_bastions (hostname, bastion) VALUES ($1::varchar, $2::varchar) ON CONFLICT (hostname) DO NOTHING",
+            &[hostname, bastion]
+        )? == 1 {
+            plan.push(format!("add SSH bastion {bastion} for {hostname}"));
+        }
+    }
+    for user in &inventory.users {
+        let name = &user.name;
+        if transaction.execute("INSERT INTO users (name) VALUES ($1::varchar) ON CONFLICT (name) DO NOTHING", &[name])? == 1 {
+            plan.push(format!("add user {name}"));
+        }
+        for key in &user.ssh_keys {
+            let inventory::UserSshKey { key_type, public_key, comment } = key;
+            check_ssh_public_key(key_type, public_key)?;
+            if transaction.execute(
+                "INSERT INTO user_ssh_keys (name, key_type, public_key, comment) VALUES ($1::varchar, $2::varchar, $3::text, $4::text)
+                 ON CONFLICT (public_key) DO NOTHING",
+                &[name, key_type, public_key, comment]
+            )? == 1 {
+                plan.push(format!("add SSH key {key_type} {public_key} for user {name}"));
+            }
+        }
+    }
+    for grant in &inventory.ssh_grants {
+        let inventory::SshGrant { user, hostname, owner, login } = grant;
+        let exists = !transaction.query(&*format!("SELECT 1 FROM ssh_grants WHERE {SSH_GRANT_CONDITION}"), &[user, hostname, owner, login])?.is_empty();
+        if exists {
+            continue;
+        }
+        let target = match (hostname, owner) {
+            (Some(hostname), None) => format!("machine {hostname}"),
+            (None, Some(owner)) => format!("owner {owner}'s machines"),
+            _ => bail!("SSH grant for user {:?} needs either a hostname or an owner", user),
+        };
+        transaction.execute(
+            "INSERT INTO ssh_grants (name, hostname, owner, login) VALUES ($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+            &[user, hostname, owner, login]
+        ).with_context(|| anyhow!("Could not add SSH grant for user {:?}", user))?;
+        let login = login.as_deref().unwrap_or("the machine's ssh_user");
+        plan.push(format!("add SSH grant for user {user} on {target} as {login}"));
+    }
+
+    Ok(plan)
+}
+
+/// Import a JSON export or a CSV of machines and addresses, committing only if `apply`
+fn import(mut transaction: Transaction, path: &Path, apply: bool) -> Result<()> {
+    let file = File::open(path).with_context(|| anyhow!("Could not open {:?}", path))?;
+    let is_csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let inventory = if is_csv {
+        inventory::from_csv(file)?
+    } else {
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| anyhow!("Could not parse {:?} as an inventory JSON document", path))?
+    };
+
+    let plan = import_inventory(&mut transaction, &inventory)?;
+    if plan.is_empty() {
+        println!("Nothing to import");
+        return Ok(());
+    }
+    for change in &plan {
+        println!("{change}");
+    }
+    if apply {
+        transaction.commit()?;
+    } else {
+        // Dropping the transaction rolls back the changes
+        println!("\nDry run: no changes were made; run again with --apply to make {} changes", plan.len());
+    }
+    Ok(())
+}
+
+/// Make the sections of the database listed in `sections` match `desired`, returning a
+/// description of each change.  Machines are created and updated but never removed, and
+/// WireGuard interfaces, private keys, the WireGuard topology, and SSH options, host keys,
+/// and access are left to the other commands.
+fn sync_inventory(
+    transaction: &mut Transaction,
+    desired: &inventory::Inventory,
+    sections: &HashSet<String>,
+    machine_fields: &HashMap<String, HashSet<String>>,
+) -> Result<Vec<String>> {
+    ensure!(desired.schema_version == inventory::SCHEMA_VERSION,
+            "Unsupported schema_version {}, expected {}", desired.schema_version, inventory::SCHEMA_VERSION);
+    for section in ["wireguard_overlays", "wireguard_exit_routes", "wireguard_hubs"] {
+        if sections.contains(section) {
+            eprintln!("Note: {section} are not synced by apply; use import or the wg-* commands");
+        }
+    }
+    for section in ["network_ssh_options", "owner_ssh_options", "ssh_bastions", "users", "ssh_grants"] {
+        if sections.contains(section) {
+            eprintln!("Note: {section} are not synced by apply; use import or the ssh-* and user commands");
+        }
+    }
+    let current = get_inventory(transaction)?;
+    let mut plan = vec![];
+
+    if sections.contains("owners") {
+        for owner in desired.owners.iter().filter(|owner| !current.owners.contains(owner)) {
+            transaction.execute("INSERT INTO owners (owner) VALUES ($1::varchar)", &[owner])?;
+            plan.push(format!("create owner {owner}"));
+        }
+    }
+    if sections.contains("providers") {
+        for provider in &desired.providers {
+            let inventory::Provider { id, name, email } = provider;
+            match current.providers.iter().find(|p| p.id == *id) {
+                None => {
+                    transaction.execute("INSERT INTO providers (id, name, email) VALUES ($1::integer, $2::varchar, $3::varchar)", &[id, name, email])?;
+                    plan.push(format!("create provider {id} {name} {email}"));
+                }
+                Some(existing) if existing != provider => {
+                    transaction.execute("UPDATE providers SET name = $2::varchar, email = $3::varchar WHERE id = $1", &[id, name, email])?;
+                    plan.push(format!("update provider {id} {} {} -> {name} {email}", existing.name, existing.email));
+                }
+                Some(_) => {}
+            }
+        }
+        // Providers were inserted with explicit IDs, so `provider add` must not reuse them
+        transaction.execute("SELECT setval(pg_get_serial_sequence('providers', 'id'), max(id)) FROM providers", &[])?;
+    }
+    if sections.contains("networks") {
+        for network in desired.networks.iter().filter(|network| !current.networks.contains(network)) {
+            transaction.execute("INSERT INTO networks (name) VALUES ($1::varchar)", &[network])?;
+            plan.push(format!("create network {network}"));
+        }
+    }
+    if sections.contains("network_links") {
+        for link in &desired.network_links {
+            let inventory::NetworkLink { name, other_network, priority } = link;
+            match current.network_links.iter().find(|l| l.name == *name && l.other_network == *other_network) {
+                None => {
+                    transaction.execute(
+                        "INSERT INTO network_links (name, other_network, priority) VALUES ($1::varchar, $2::varchar, $3::integer)",
+                        &[name, other_network, priority]
+                    )?;
+                    plan.push(format!("create network link {name} {other_network} priority {priority}"));
+                }
+                Some(existing) if existing.priority != *priority => {
+                    transaction.execute(
+                        "UPDATE network_links SET priority = $3::integer WHERE name = $1 AND other_network = $2",
+                        &[name, other_network, priority]
+                    )?;
+                    plan.push(format!("update network link {name} {other_network} priority {} -> {priority}", existing.priority));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    if sections.contains("machines") {
+        let new_machines = desired.machines
+            .iter()
+            .filter(|m| !current.machines.iter().any(|c| c.hostname == m.hostname))
+            .cloned()
+            .collect();
+        let new_machines = inventory::Inventory { schema_version: inventory::SCHEMA_VERSION, machines: new_machines, ..Default::default() };
+        for change in import_inventory(transaction, &new_machines)? {
+            plan.push(change.replacen("add ", "create ", 1));
+        }
+
+        for existing in &current.machines {
+            let hostname = &existing.hostname;
+            let machine = match desired.machines.iter().find(|m| m.hostname == *hostname) {
+                Some(machine) => machine,
+                None => {
+                    eprintln!("Note: machine {hostname} is not in the file and was left alone; remove it with `i rm`");
+                    continue;
+                }
+            };
+            // A field missing from the machine leaves the value unchanged, and a null clears it
+            let fields = machine_fields.get(hostname);
+            let has_field = |field: &str| fields.is_some_and(|fields| fields.contains(field));
+            let mut changes = vec![];
+            let owner = match has_field("owner") {
+                true => machine.owner.as_ref().with_context(|| anyhow!("Machine {:?} must have an owner", hostname))?,
+                false => existing.owner.as_ref().unwrap(),
+            };
+            let provider_id = if has_field("provider_id") { machine.provider_id } else { existing.provider_id };
+            let provider_reference = if has_field("provider_reference") { &machine.provider_reference } else { &existing.provider_reference };
+            if Some(owner) != existing.owner.as_ref() {
+                changes.push(format!("owner {} -> {owner}", existing.owner.as_deref().unwrap_or_default()));
+            }
+            if provider_id != existing.provider_id {
+                changes.push(format!("provider_id {:?} -> {:?}", existing.provider_id, provider_id));
+            }
+            if *provider_reference != existing.provider_reference {
+                changes.push(format!("provider_reference {:?} -> {:?}", existing.provider_reference, provider_reference));
+            }
+            if !changes.is_empty() {
+                transaction.execute(
+                    "UPDATE machines SET owner = $2::varchar, provider_id = $3::integer, provider_reference = $4::text WHERE hostname = $1",
+                    &[hostname, owner, &provider_id, provider_reference]
+                )?;
+            }
+            // Like `import`, null for both ssh_port and ssh_user means no SSH server,
+            // and null for just one of them means its default
+            let ssh_port = if has_field("ssh_port") { machine.ssh_port } else { existing.ssh_port };
+            let ssh_user = if has_field("ssh_user") { machine.ssh_user.clone() } else { existing.ssh_user.clone() };
+            if (ssh_port, &ssh_user) != (existing.ssh_port, &existing.ssh_user) {
+                let is_bastion = transaction.query_opt("SELECT 1 FROM ssh_bastions WHERE bastion = $1 LIMIT 1", &[hostname])?.is_some();
+                ensure!(!is_bastion || ssh_port.is_some() || ssh_user.is_some(),
+                        "Can't remove the SSH server of machine {:?} because it is a bastion; remove it with `ssh-bastion rm` first", hostname);
+                transaction.execute("DELETE FROM ssh_servers WHERE hostname = $1", &[hostname])?;
+                if ssh_port.is_some() || ssh_user.is_some() {
+                    insert_ssh_server(transaction, hostname, inventory_port(ssh_port, "SSH port")?, ssh_user.clone())
+                        .with_context(|| anyhow!("Could not set SSH server of machine {:?}", hostname))?;
+                }
+                changes.push(format!("ssh {:?}@{:?} -> {:?}@{:?}", existing.ssh_user, existing.ssh_port, ssh_user, ssh_port));
+            }
+            if !changes.is_empty() {
+                plan.push(format!("update machine {hostname}: {}", changes.join(", ")));
+            }
+
+            if !has_field("addresses") {
+                continue;
+            }
+            for address in &machine.addresses {
+                let inventory::MachineAddress { network, address: ip, ssh_port, wireguard_port } = address;
+                match existing.addresses.iter().find(|a| a.network == *network && a.address == *ip) {
+                    None => {
+                        transaction.execute(
+                            "INSERT INTO machine_addresses (hostname, network, address, ssh_port, wireguard_port)
+                             VALUES ($1::varchar, $2::varchar, $3::inet, $4::integer, $5::integer)",
+                            &[hostname, network, ip, ssh_port, wireguard_port]
+                        ).with_context(|| anyhow!("Could not add address {} to machine {:?}", ip, hostname))?;
+                        plan.push(format!("create address {hostname} {network} {ip}"));
+                    }
+                    Some(existing_address) if existing_address != address => {
+                        transaction.execute(
+                            "UPDATE machine_addresses SET ssh_port = $4::integer, wireguard_port = $5::integer
+                             WHERE hostname = $1 AND network = $2 AND address = $3",
+                            &[hostname, network, ip, ssh_port, wireguard_port]
+                        )?;
+                        plan.push(format!("update address {hostname} {network} {ip}: ports {:?}/{:?} -> {:?}/{:?}",
+                                          existing_address.ssh_port, existing_address.wireguard_port, ssh_port, wireguard_port));
+                    }
+                    Some(_) => {}
+                }
+            }
+            for address in &existing.addresses {
+                let inventory::MachineAddress { network, address: ip, .. } = address;
+                if !machine.addresses.iter().any(|a| a.network == *network && a.address == *ip) {
+                    transaction.execute(
+                        "DELETE FROM machine_addresses WHERE hostname = $1 AND network = $2 AND address = $3",
+                        &[hostname, network, ip]
+                    )?;
+                    plan.push(format!("delete address {hostname} {network} {ip}"));
+                }
+            }
+        }
+    }
+
+    if sections.contains("wireguard_keepalives") {
+        for keepalive in &desired.wireguard_keepalives {
+            let inventory::WireguardKeepalive { source_machine, target_machine, interval_sec } = keepalive;
+            match current.wireguard_keepalives.iter().find(|k| k.source_machine == *source_machine && k.target_machine == *target_machine) {
+                None => {
+                    transaction.execute(
+                        "INSERT INTO wireguard_keepalives (source_machine, target_machine, interval_sec)
+                         VALUES ($1::varchar, $2::varchar, $3::integer)",
+                        &[source_machine, target_machine, interval_sec]
+                    )?;
+                    plan.push(format!("create WireGuard keepalive {source_machine} {target_machine} {interval_sec}"));
+                }
+                Some(existing) if existing.interval_sec != *interval_sec => {
+                    transaction.execute(
+                        "UPDATE wireguard_keepalives SET interval_sec = $3::integer WHERE source_machine = $1 AND target_machine = $2",
+                        &[source_machine, target_machine, interval_sec]
+                    )?;
+                    plan.push(format!("update WireGuard keepalive {source_machine} {target_machine} {} -> {interval_sec}", existing.interval_sec));
+                }
+                Some(_) => {}
+            }
+        }
+        for keepalive in &current.wireguard_keepalives {
+            let inventory::WireguardKeepalive { source_machine, target_machine, .. } = keepalive;
+            if !desired.wireguard_keepalives.iter().any(|k| k.source_machine == *source_machine && k.target_machine == *target_machine) {
+                transaction.execute(
+                    "DELETE FROM wireguard_keepalives WHERE source_machine = $1 AND target_machine = $2",
+                    &[source_machine, target_machine]
+                )?;
+                plan.push(format!("delete WireGuard keepalive {source_machine} {target_machine}"));
+            }
+        }
+    }
+
+    // Deleted last, after the rows that may have referenced them are updated
+    if sections.contains("network_links") {
+        for link in &current.network_links {
+            let inventory::NetworkLink { name, other_network, .. } = link;
+            if !desired.network_links.iter().any(|l| l.name == *name && l.other_network == *other_network) {
+                transaction.execute("DELETE FROM network_links WHERE name = $1 AND other_network = $2", &[name, other_network])?;
+                plan.push(format!("delete network link {name} {other_network}"));
+            }
+        }
+    }
+    if sections.contains("networks") {
+        for network in current.networks.iter().filter(|network| !desired.networks.contains(network)) {
+            transaction.execute("DELETE FROM networks WHERE name = $1", &[network])
+                .with_context(|| anyhow!("Could not delete network {:?}", network))?;
+            plan.push(format!("delete network {network}"));
+        }
+    }
+    if sections.contains("providers") {
+        for provider in current.providers.iter().filter(|p| !desired.providers.iter().any(|d| d.id == p.id)) {
+            transaction.execute("DELETE FROM providers WHERE id = $1", &[&provider.id])
+                .with_context(|| anyhow!("Could not delete provider {}", provider.id))?;
+            plan.push(format!("delete provider {} {}", provider.id, provider.name));
+        }
+    }
+    if sections.contains("owners") {
+        for owner in current.owners.iter().filter(|owner| !desired.owners.contains(owner)) {
+            transaction.execute("DELETE FROM owners WHERE owner = $1", &[owner])
+                .with_context(|| anyhow!("Could not delete owner {:?}", owner))?;
+            plan.push(format!("delete owner {owner}"));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Make the database match a desired-state JSON or TOML file in the format written by `export`.
+/// Only the top-level sections present in the file, and the fields present for each machine, are synced.
+fn apply(mut transaction: Transaction, path: &Path, dry_run: bool) -> Result<()> {
+    let content = fs::read_to_string(path).with_context(|| anyhow!("Could not read {:?}", path))?;
+    let is_toml = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+    let value: serde_json::Value = if is_toml {
+        toml::from_str(&content).with_context(|| anyhow!("Could not parse {:?} as TOML", path))?
+    } else {
+        serde_json::from_str(&content).with_context(|| anyhow!("Could not parse {:?} as JSON", path))?
+    };
+    let sections = match value.as_object() {
+        Some(object) => object.keys().cloned().collect::<HashSet<_>>(),
+        None => bail!("{:?} does not contain a table of sections", path),
+    };
+    // hostname -> the fields present for that machine
+    let machine_fields = value.get("machines")
+        .and_then(|machines| machines.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|machine| {
+            let machine = machine.as_object()?;
+            let hostname = machine.get("hostname")?.as_str()?;
+            Some((hostname.to_string(), machine.keys().cloned().collect::<HashSet<_>>()))
+        })
+        .collect::<HashMap<_, _>>();
+    let desired: inventory::Inventory = serde_json::from_value(value)
+        .with_context(|| anyhow!("Could not parse {:?} as an inventory document", path))?;
+
+    let plan = sync_inventory(&mut transaction, &desired, &sections, &machine_fields)?;
+    if plan.is_empty() {
+        println!("Nothing to change");
+        return Ok(());
+    }
+    for change in &plan {
+        println!("{change}");
+    }
+    if dry_run {
+        // Dropping the transaction rolls back the changes
+        println!("\nDry run: no changes were made");
+    } else {
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn print_wireguard_privkey(transaction: &mut Transaction, hostname: &str, interface: &str) -> Result<()> {
+    let rows = transaction.query(
+        "SELECT machines.hostname, wireguard_privkey FROM machines
+         LEFT JOIN wireguard_interfaces ON machines.hostname = wireguard_interfaces.hostname AND interface = $2
+         WHERE machines.hostname = $1", &[&hostname, &interface]
+    )?;
+    ensure!(!rows.is_empty(), "Could not find machine {:?} in database", hostname);
+    let row = &rows[0];
+    let privkey: Option<&str> = row.get(1);
+    ensure!(privkey.is_some(), "Machine {:?} does not have WireGuard interface {:?}", hostname, interface);
+    println!("{}", privkey.unwrap());
+    Ok(())
+}
+
+fn get_existing_wireguard_ipv4_addresses(transaction: &mut Transaction, interface: &str) -> Result<impl Iterator<Item=Ipv4Addr>> {
+    let iter = transaction.query("SELECT wireguard_ipv4_address FROM wireguard_interfaces WHERE interface = $1", &[&interface])?
+        .into_iter()
+        .filter_map(|row| {
+            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
+            wireguard_ipaddr.map(get_ipv4addr)
+        });
+    Ok(iter)
+}
+
+fn get_existing_wireguard_ipv6_addresses(transaction: &mut Transaction, interface: &str) -> Result<impl Iterator<Item=Ipv6Addr>> {
+    let iter = transaction.query("SELECT wireguard_ipv6_address FROM wireguard_interfaces WHERE interface = $1", &[&interface])?
+        .into_iter()
+        .filter_map(|row| {
+            let wireguard_ipaddr: Option<IpAddr> = row.get(0);
+            wireguard_ipaddr.map(get_ipv6addr)
+        });
+    Ok(iter)
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn increment_ipv4_address(ip: &Ipv4Addr) -> Option<Ipv4Addr> {
+    let mut octets = ip.octets();
+    if octets == [255, 255, 255, 255] {
+        return None;
+    }
+    for i in (0..4).rev() {
+        if octets[i] < 255 {
+            octets[i] += 1;
+            break;
+        } else {
+            octets[i] = 0;
+        }
+    }
+    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+fn increment_ipv6_address(ip: &Ipv6Addr) -> Option<Ipv6Addr> {
+    let mut segments = ip.segments();
+    if segments == [0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff] {
+        return None;
+    }
+    for i in (0..8).rev() {
+        if segments[i] < 0xffff {
+            segments[i] += 1;
+            break;
+        } else {
+            segments[i] = 0;
+        }
+    }
+    Some(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]))
+}
+
+/// Return a netmask with the top `prefix_len` bits set, for an address `width` bits wide
+fn prefix_mask(width: u32, prefix_len: u32) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix_len)) >> (128 - width)
+    }
+}
+
+/// Return the smallest list of (network, prefix_len) pairs that cover every
+/// address in `address`'s family except for `address` itself
+fn default_route_excluding(address: IpAddr) -> Vec<(IpAddr, u8)> {
+    let (width, value) = match address {
+        IpAddr::V4(ip) => (32, u128::from(u32::from(ip))),
+        IpAddr::V6(ip) => (128, u128::from(ip)),
+    };
+    (0..width).map(|i| {
+        // Keep the first `i` bits of `address`, flip the next one, and cover
+        // everything after that.
+        let network = (value ^ (1 << (width - 1 - i))) & prefix_mask(width, i + 1);
+        let network = match address {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(network as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(network)),
+        };
+        (network, (i + 1) as u8)
+    }).collect()
+}
+
+fn get_unused_wireguard_ipv4_address(transaction: &mut Transaction, interface: &str, start_ip: Ipv4Addr, end_ip: Ipv4Addr) -> Result<Option<Ipv4Addr>> {
+    let existing = get_existing_wireguard_ipv4_addresses(transaction, interface)?.collect::<HashSet<Ipv4Addr>>();
+    let ip_iter = iter::successors(Some(start_ip), increment_ipv4_address);
+    for proposed_ip in ip_iter {
+        if !existing.contains(&proposed_ip) {
+            return Ok(Some(proposed_ip));
+        }
+        if proposed_ip == end_ip {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+fn get_unused_wireguard_ipv6_address(transaction: &mut Transaction, interface: &str, start_ip: Ipv6Addr, end_ip: Ipv6Addr) -> Result<Option<Ipv6Addr>> {
+    let existing = get_existing_wireguard_ipv6_addresses(transaction, interface)?.collect::<HashSet<Ipv6Addr>>();
+    let ip_iter = iter::successors(Some(start_ip), increment_ipv6_address);
+    for proposed_ip in ip_iter {
+        if !existing.contains(&proposed_ip) {
             return Ok(Some(proposed_ip));
         }
         if proposed_ip == end_ip {
@@ -461,26 +1743,94 @@ fn env_var(var: &str) -> Result<String> {
     env::var(var).with_context(|| anyhow!("Could not get variable {:?} from environment", var))
 }
 
-#[allow(clippy::too_many_arguments)]
-fn add_machine(
-    mut transaction: Transaction,
+/// The range of addresses WireGuard interfaces with some name are allocated from
+struct WireguardPool {
+    ipv4_start: Ipv4Addr,
+    ipv4_end: Ipv4Addr,
+    ipv6_start: Ipv6Addr,
+    ipv6_end: Ipv6Addr,
+}
+
+/// Get the address pool for `interface` from wireguard_overlays, falling back
+/// to the pool in the environment.
+fn get_wireguard_pool(transaction: &mut Transaction, interface: &str) -> Result<WireguardPool> {
+    let rows = transaction.query(
+        "SELECT ipv4_start, ipv4_end, ipv6_start, ipv6_end FROM wireguard_overlays
+         WHERE interface = $1 AND ipv4_start IS NOT NULL", &[&interface]
+    )?;
+    if let Some(row) = rows.first() {
+        return Ok(WireguardPool {
+            ipv4_start: get_ipv4addr(row.get(0)),
+            ipv4_end:   get_ipv4addr(row.get(1)),
+            ipv6_start: get_ipv6addr(row.get(2)),
+            ipv6_end:   get_ipv6addr(row.get(3)),
+        });
+    }
+    Ok(WireguardPool {
+        ipv4_start: env_var("WIREGUARD_IPV4_START")?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_START as an Ipv4Addr")?,
+        ipv4_end:   env_var("WIREGUARD_IPV4_END")  ?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_END as an Ipv4Addr")?,
+        ipv6_start: env_var("WIREGUARD_IPV6_START")?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_START as an Ipv6Addr")?,
+        ipv6_end:   env_var("WIREGUARD_IPV6_END")  ?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_END as an Ipv6Addr")?,
+    })
+}
+
+/// Add a WireGuard interface with a new keypair to a machine, selecting unused
+/// addresses from the interface's pool if they are not provided.
+fn insert_wireguard_interface(
+    transaction: &mut Transaction,
     hostname: &str,
-    owner: Option<String>,
-    ssh_port: Option<u16>,
-    ssh_user: Option<String>,
+    interface: &str,
     wireguard_ipv4_address: Option<Ipv4Addr>,
     wireguard_ipv6_address: Option<Ipv6Addr>,
     wireguard_port: Option<u16>,
-    provider: Option<i32>,
-    provider_reference: Option<String>,
-) -> Result<()> {
-    // Required environmental variables
-    let ipv4_start = env_var("WIREGUARD_IPV4_START")?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_START as an Ipv4Addr")?;
-    let ipv4_end   = env_var("WIREGUARD_IPV4_END")  ?.parse::<Ipv4Addr>().context("Could not parse WIREGUARD_IPV4_END as an Ipv4Addr")?;
-    let ipv6_start = env_var("WIREGUARD_IPV6_START")?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_START as an Ipv6Addr")?;
-    let ipv6_end   = env_var("WIREGUARD_IPV6_END")  ?.parse::<Ipv6Addr>().context("Could not parse WIREGUARD_IPV6_END as an Ipv6Addr")?;
+) -> Result<(Ipv4Addr, Ipv6Addr, u16)> {
+    let used_ports = transaction.query("SELECT wireguard_port FROM wireguard_interfaces WHERE hostname = $1", &[&hostname])?
+        .into_iter()
+        .map(|row| row.get::<_, i32>(0))
+        .collect::<HashSet<_>>();
+    let wireguard_port = match wireguard_port {
+        Some(port) => {
+            ensure!(!used_ports.contains(&i32::from(port)), "Machine {:?} already has a WireGuard interface on port {}", hostname, port);
+            port
+        },
+        None => {
+            // Each interface on a machine needs its own port, so use the first free one
+            let default_port = env_var("DEFAULT_WIREGUARD_PORT")
+                .context("No WireGuard port was provided, and could not get variable \"DEFAULT_WIREGUARD_PORT\" from environment")?
+                .parse::<u16>()
+                .context("No WireGuard port was provided, and could not parse DEFAULT_WIREGUARD_PORT as a u16")?;
+            (default_port..=u16::MAX)
+                .find(|port| !used_ports.contains(&i32::from(*port)))
+                .with_context(|| anyhow!("Could not find an unused WireGuard port for machine {:?}", hostname))?
+        },
+    };
+    let pool = get_wireguard_pool(transaction, interface)?;
+    let wireguard_ipv4_address = match wireguard_ipv4_address {
+        Some(ip) => ip,
+        None => {
+            get_unused_wireguard_ipv4_address(transaction, interface, pool.ipv4_start, pool.ipv4_end)?
+                .with_context(|| anyhow!("Could not find an unused WireGuard IPv4 address in the address pool for {:?}", interface))?
+        }
+    };
+    let wireguard_ipv6_address = match wireguard_ipv6_address {
+        Some(ip) => ip,
+        None => {
+            get_unused_wireguard_ipv6_address(transaction, interface, pool.ipv6_start, pool.ipv6_end)?
+                .with_context(|| anyhow!("Could not find an unused WireGuard IPv6 address in the address pool for {:?}", interface))?
+        }
+    };
+    let keypair = wireguard::generate_keypair()?;
 
-    // Optional environmental variables
+    transaction.execute(
+        "INSERT INTO wireguard_interfaces (hostname, interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey)
+                VALUES ($1::varchar, $2::varchar, $3::inet, $4::inet, $5::integer, $6::varchar, $7::varchar)",
+        &[&hostname, &interface, &IpAddr::V4(wireguard_ipv4_address), &IpAddr::V6(wireguard_ipv6_address), &i32::from(wireguard_port), &str::from_utf8(&keypair.privkey).unwrap(), &str::from_utf8(&keypair.pubkey).unwrap()]
+    )?;
+    Ok((wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port))
+}
+
+/// Add an SSH server to a machine, using defaults from the environment for unspecified values
+fn insert_ssh_server(transaction: &mut Transaction, hostname: &str, ssh_port: Option<u16>, ssh_user: Option<String>) -> Result<()> {
     let ssh_port = unwrap_or_else!(
         ssh_port,
         env_var("DEFAULT_SSH_PORT")
@@ -493,13 +1843,23 @@ fn add_machine(
         env_var("DEFAULT_SSH_USER")
             .context("No SSH user was provided, and could not get variable \"DEFAULT_SSH_USER\" from environment")?
     );
-    let wireguard_port = unwrap_or_else!(
-        wireguard_port,
-        env_var("DEFAULT_WIREGUARD_PORT")
-            .context("No WireGuard port was provided, and could not get variable \"DEFAULT_WIREGUARD_PORT\" from environment")?
-            .parse::<u16>()
-            .context("No WireGuard port was provided, and could not parse DEFAULT_WIREGUARD_PORT as a u16")?
-    );
+    transaction.execute(
+        "INSERT INTO ssh_servers (hostname, ssh_port, ssh_user)
+                VALUES ($1::varchar, $2::integer, $3::varchar)",
+        &[&hostname, &i32::from(ssh_port), &ssh_user]
+    )?;
+    Ok(())
+}
+
+/// Insert a machine, using defaults from the environment for unspecified values
+fn insert_machine(
+    transaction: &mut Transaction,
+    hostname: &str,
+    owner: Option<String>,
+    provider: Option<i32>,
+    provider_reference: Option<String>,
+) -> Result<()> {
+    // Optional environmental variables
     let owner = unwrap_or_else!(
         owner,
         env_var("DEFAULT_OWNER")
@@ -513,39 +1873,641 @@ fn add_machine(
         }
     );
 
-    let wireguard_ipv4_address = match wireguard_ipv4_address {
-        Some(ip) => ip,
-        None => {
-            get_unused_wireguard_ipv4_address(&mut transaction, ipv4_start, ipv4_end)?
-                .context("Could not find an unused WireGuard IPv4 address between WIREGUARD_IPV4_START and WIREGUARD_IPV4_END")?
-        }
-    };
-    let wireguard_ipv6_address = match wireguard_ipv6_address {
-        Some(ip) => ip,
+    transaction.execute(
+        "INSERT INTO machines (hostname, owner, provider_id, provider_reference)
+                VALUES ($1::varchar, $2::varchar, $3, $4)",
+        &[&hostname, &owner, &provider_id, &provider_reference]
+    )?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_machine(
+    mut transaction: Transaction,
+    hostname: &str,
+    owner: Option<String>,
+    ssh: bool,
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    wireguard: bool,
+    wireguard_interface: &str,
+    wireguard_ipv4_address: Option<Ipv4Addr>,
+    wireguard_ipv6_address: Option<Ipv6Addr>,
+    wireguard_port: Option<u16>,
+    provider: Option<i32>,
+    provider_reference: Option<String>,
+) -> Result<()> {
+    insert_machine(&mut transaction, hostname, owner, provider, provider_reference)?;
+    if ssh {
+        insert_ssh_server(&mut transaction, hostname, ssh_port, ssh_user)?;
+    }
+    if wireguard {
+        insert_wireguard_interface(&mut transaction, hostname, wireguard_interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port)?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_ssh_servers(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "PORT", "USER"])?;
+    for row in transaction.query("SELECT hostname, ssh_port, ssh_user FROM ssh_servers ORDER BY hostname", &[])? {
+        let hostname: String = row.get(0);
+        let ssh_port: i32 = row.get(1);
+        let ssh_user: String = row.get(2);
+        writeln!(tw, "{hostname}\t{ssh_port}\t{ssh_user}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_ssh_server(mut transaction: Transaction, hostname: &str, ssh_port: Option<u16>, ssh_user: Option<String>) -> Result<()> {
+    let exists = transaction.query_opt("SELECT 1 FROM ssh_servers WHERE hostname = $1", &[&hostname])?.is_some();
+    ensure!(!exists, "Machine {:?} already has an SSH server", hostname);
+    insert_ssh_server(&mut transaction, hostname, ssh_port, ssh_user)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn remove_ssh_server(mut transaction: Transaction, hostname: &str) -> Result<()> {
+    let num_deleted = transaction.execute("DELETE FROM ssh_servers WHERE hostname = $1", &[&hostname])?;
+    ensure!(num_deleted == 1, "Could not find SSH server for machine {:?} in database", hostname);
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_ssh_options(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["SCOPE", "NAME", "OPTION", "VALUE"])?;
+    for (scope_name, scope) in [("machine", SshOptionScope::Machine), ("network", SshOptionScope::Network), ("owner", SshOptionScope::Owner)] {
+        let query = format!("SELECT {}, option, value FROM {} ORDER BY ({}, lower(option))", scope.column(), scope.table(), scope.column());
+        for row in transaction.query(query.as_str(), &[])? {
+            let name: String = row.get(0);
+            let option: String = row.get(1);
+            let value: String = row.get(2);
+            writeln!(tw, "{scope_name}\t{name}\t{option}\t{value}")?;
+        }
+    }
+    print_tabwriter(tw)
+}
+
+/// Check that an ssh_config option is one that `ssh-config` does not write itself, and that its value fits on one line
+fn check_ssh_option(option: &str, value: Option<&str>) -> Result<()> {
+    ensure!(!option.is_empty() && option.chars().all(|c| c.is_ascii_alphanumeric()), "Invalid ssh_config option {:?}", option);
+    if let Some(reserved) = RESERVED_SSH_OPTIONS.iter().find(|reserved| reserved.eq_ignore_ascii_case(option)) {
+        ensure!(*reserved != "ProxyJump", "ProxyJump is written by ssh-config; use `ssh-bastion add` instead");
+        bail!("{} is written by ssh-config and cannot be set as an option", reserved);
+    }
+    if let Some(value) = value {
+        ensure!(!value.is_empty() && !value.contains('\n'), "ssh_config option values must be non-empty and cannot contain newlines");
+    }
+    Ok(())
+}
+
+/// Add an ssh_config option unless `name` already has it, however it was capitalized,
+/// returning whether it was added
+fn insert_ssh_option(transaction: &mut Transaction, scope: SshOptionScope, name: &str, option: &str, value: &str) -> Result<bool> {
+    check_ssh_option(option, Some(value))?;
+    let num_inserted = transaction.execute(
+        &*format!(
+            "INSERT INTO {table} ({column}, option, value) SELECT $1::varchar, $2::varchar, $3::text
+             WHERE NOT EXISTS (SELECT 1 FROM {table} WHERE {column} = $1 AND lower(option) = lower($2))",
+            table = scope.table(), column = scope.column(),
+        ),
+        &[&name, &option, &value],
+    )?;
+    Ok(num_inserted == 1)
+}
+
+fn set_ssh_option(mut transaction: Transaction, scope: SshOptionScope, name: &str, option: &str, value: Option<&str>) -> Result<()> {
+    check_ssh_option(option, value)?;
+    // ssh_config keywords are case-insensitive, so replace the option however it was capitalized
+    let num_deleted = transaction.execute(
+        &*format!("DELETE FROM {} WHERE {} = $1 AND lower(option) = lower($2)", scope.table(), scope.column()),
+        &[&name, &option],
+    )?;
+    match value {
+        Some(value) => {
+            transaction.execute(
+                &*format!("INSERT INTO {} ({}, option, value) VALUES ($1::varchar, $2::varchar, $3::text)", scope.table(), scope.column()),
+                &[&name, &option, &value],
+            )?;
+        },
         None => {
-            get_unused_wireguard_ipv6_address(&mut transaction, ipv6_start, ipv6_end)?
-                .context("Could not find an unused WireGuard IPv6 address between WIREGUARD_IPV6_START and WIREGUARD_IPV6_END")?
+            ensure!(num_deleted == 1, "Could not find ssh_config option {:?} for {:?} in database", option, name);
+        },
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_ssh_bastions(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "BASTION"])?;
+    for row in transaction.query("SELECT hostname, bastion FROM ssh_bastions ORDER BY hostname", &[])? {
+        let hostname: String = row.get(0);
+        let bastion: String = row.get(1);
+        writeln!(tw, "{hostname}\t{bastion}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_ssh_bastion(mut transaction: Transaction, hostname: &str, bastion: &str) -> Result<()> {
+    let has_ssh_server = transaction.query_opt("SELECT 1 FROM ssh_servers WHERE hostname = $1", &[&bastion])?.is_some();
+    ensure!(has_ssh_server, "Machine {:?} does not have an SSH server", bastion);
+    transaction.execute(
+        "INSERT INTO ssh_bastions (hostname, bastion) VALUES ($1::varchar, $2::varchar)
+         ON CONFLICT (hostname) DO UPDATE SET bastion = excluded.bastion",
+        &[&hostname, &bastion],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn remove_ssh_bastion(mut transaction: Transaction, hostname: &str) -> Result<()> {
+    let num_deleted = transaction.execute("DELETE FROM ssh_bastions WHERE hostname = $1", &[&hostname])?;
+    ensure!(num_deleted == 1, "Could not find bastion for {:?} in database", hostname);
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_ssh_host_keys(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "TYPE", "PUBLIC KEY"])?;
+    for row in transaction.query("SELECT hostname, key_type, public_key FROM ssh_host_keys ORDER BY (hostname, key_type)", &[])? {
+        let hostname: String = row.get(0);
+        let key_type: String = row.get(1);
+        let public_key: String = row.get(2);
+        writeln!(tw, "{hostname}\t{key_type}\t{public_key}")?;
+    }
+    print_tabwriter(tw)
+}
+
+/// Check that a key type and public key look like those in an SSH public key
+fn check_ssh_public_key(key_type: &str, public_key: &str) -> Result<()> {
+    ensure!(!key_type.is_empty() && key_type.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-@.".contains(c)),
+            "Invalid SSH key type {:?}", key_type);
+    ensure!(!public_key.is_empty() && public_key.chars().all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c)),
+            "SSH public key {:?} is not base64", public_key);
+    Ok(())
+}
+
+/// Add or replace a machine's host key, returning whether anything changed
+fn upsert_ssh_host_key(transaction: &mut Transaction, hostname: &str, key_type: &str, public_key: &str) -> Result<bool> {
+    check_ssh_public_key(key_type, public_key)?;
+    let num_changed = transaction.execute(
+        "INSERT INTO ssh_host_keys (hostname, key_type, public_key) VALUES ($1::varchar, $2::varchar, $3::text)
+         ON CONFLICT (hostname, key_type) DO UPDATE SET public_key = excluded.public_key
+         WHERE ssh_host_keys.public_key <> excluded.public_key",
+        &[&hostname, &key_type, &public_key],
+    )?;
+    Ok(num_changed == 1)
+}
+
+fn add_ssh_host_key(mut transaction: Transaction, hostname: &str, key_type: &str, public_key: &str) -> Result<()> {
+    upsert_ssh_host_key(&mut transaction, hostname, key_type, public_key)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn remove_ssh_host_key(mut transaction: Transaction, hostname: &str, key_type: &str) -> Result<()> {
+    let num_deleted = transaction.execute("DELETE FROM ssh_host_keys WHERE hostname = $1 AND key_type = $2", &[&hostname, &key_type])?;
+    ensure!(num_deleted == 1, "Could not find {} host key for {:?} in database", key_type, hostname);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// A host key from a line of `ssh-keyscan` or known_hosts output
+#[derive(Debug, PartialEq, Eq)]
+struct ScannedHostKey {
+    /// Host names and addresses, without any [...]:port
+    hosts: Vec<String>,
+    key_type: String,
+    public_key: String,
+}
+
+/// Parse a line of `ssh-keyscan` output, returning `None` for comments and blank lines
+fn parse_ssh_keyscan_line(line: &str) -> Result<Option<ScannedHostKey>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    ensure!(fields.len() >= 3, "Expected host, key type, and public key");
+    ensure!(!fields[0].starts_with('|'), "Hashed host names are not supported; run ssh-keyscan without -H");
+    let hosts = fields[0].split(',')
+        .map(|host| {
+            // [host]:port
+            match host.strip_prefix('[').and_then(|rest| rest.split_once("]:")) {
+                Some((host, _port)) => host.to_string(),
+                None => host.to_string(),
+            }
+        })
+        .collect();
+    Ok(Some(ScannedHostKey { hosts, key_type: fields[1].to_string(), public_key: fields[2].to_string() }))
+}
+
+/// Find the machine with a hostname or address of `host`
+fn find_machine_by_host(transaction: &mut Transaction, host: &str) -> Result<Option<String>> {
+    let rows = match host.parse::<IpAddr>() {
+        Ok(address) => transaction.query(
+            "SELECT hostname FROM machine_addresses WHERE address = $1
+             UNION
+             SELECT hostname FROM wireguard_interfaces WHERE wireguard_ipv4_address = $1 OR wireguard_ipv6_address = $1",
+            &[&address],
+        )?,
+        Err(_) => transaction.query("SELECT hostname FROM machines WHERE hostname = $1", &[&host])?,
+    };
+    match rows.len() {
+        0 => Ok(None),
+        1 => Ok(Some(rows[0].get(0))),
+        _ => bail!("Address {:?} belongs to more than one machine", host),
+    }
+}
+
+/// Add host keys from `ssh-keyscan` output, finding the machine for each key
+/// by its host name or address unless `hostname` is given
+fn import_ssh_host_keys(mut transaction: Transaction, path: &Path, hostname: Option<&str>) -> Result<()> {
+    let content = fs::read_to_string(path).with_context(|| anyhow!("Could not read {:?}", path))?;
+    for (i, line) in content.lines().enumerate() {
+        let key = unwrap_or_else!(
+            parse_ssh_keyscan_line(line).with_context(|| anyhow!("Could not parse line {} of {:?}", i + 1, path))?,
+            continue
+        );
+        let machine = match hostname {
+            Some(hostname) => hostname.to_string(),
+            None => {
+                let mut machines = vec![];
+                for host in &key.hosts {
+                    if let Some(machine) = find_machine_by_host(&mut transaction, host)? {
+                        machines.push(machine);
+                    }
+                }
+                machines.sort();
+                machines.dedup();
+                match machines.as_slice() {
+                    [machine] => machine.clone(),
+                    [] => bail!("Line {} of {:?}: could not find a machine for {}", i + 1, path, key.hosts.join(",")),
+                    _ => bail!("Line {} of {:?}: {} belong to different machines", i + 1, path, key.hosts.join(",")),
+                }
+            }
+        };
+        if upsert_ssh_host_key(&mut transaction, &machine, &key.key_type, &key.public_key)? {
+            println!("set {} host key for {}", key.key_type, machine);
         }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_users(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["NAME", "KEYS", "GRANTS"])?;
+    for row in transaction.query(
+        "SELECT name,
+                (SELECT count(*) FROM user_ssh_keys WHERE user_ssh_keys.name = users.name),
+                (SELECT count(*) FROM ssh_grants WHERE ssh_grants.name = users.name)
+         FROM users ORDER BY name", &[]
+    )? {
+        let name: String = row.get(0);
+        let keys: i64 = row.get(1);
+        let grants: i64 = row.get(2);
+        writeln!(tw, "{name}\t{keys}\t{grants}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_user(mut transaction: Transaction, name: &str) -> Result<()> {
+    transaction.execute("INSERT INTO users (name) VALUES ($1::varchar)", &[&name])?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Remove a user along with their SSH keys and grants
+fn remove_user(mut transaction: Transaction, name: &str) -> Result<()> {
+    transaction.execute("DELETE FROM ssh_grants WHERE name = $1", &[&name])?;
+    transaction.execute("DELETE FROM user_ssh_keys WHERE name = $1", &[&name])?;
+    let num_deleted = transaction.execute("DELETE FROM users WHERE name = $1", &[&name])?;
+    ensure!(num_deleted == 1, "Could not find user {:?} in database", name);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// An SSH public key in the format of a .pub file
+#[derive(Debug, PartialEq, Eq)]
+struct SshPublicKey {
+    key_type: String,
+    public_key: String,
+    comment: Option<String>,
+}
+
+/// Parse an SSH public key in the format of a .pub file: a key type, a base64 public key, and an optional comment
+fn parse_ssh_public_key(line: &str) -> Result<SshPublicKey> {
+    let mut fields = line.split_whitespace();
+    let (key_type, public_key) = match (fields.next(), fields.next()) {
+        (Some(key_type), Some(public_key)) => (key_type, public_key),
+        _ => bail!("Expected an SSH public key like \"ssh-ed25519 AAAA... comment\", got {:?}", line),
     };
-    let keypair = wireguard::generate_keypair()?;
+    check_ssh_public_key(key_type, public_key)?;
+    let comment = fields.join(" ");
+    Ok(SshPublicKey {
+        key_type: key_type.to_string(),
+        public_key: public_key.to_string(),
+        comment: (!comment.is_empty()).then_some(comment),
+    })
+}
+
+fn list_user_ssh_keys(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["NAME", "TYPE", "PUBLIC KEY", "COMMENT"])?;
+    for row in transaction.query("SELECT name, key_type, public_key, comment FROM user_ssh_keys ORDER BY (name, key_type, public_key)", &[])? {
+        let name: String = row.get(0);
+        let key_type: String = row.get(1);
+        let public_key: String = row.get(2);
+        write_table_cell(&mut tw, &name)?;
+        write_table_cell(&mut tw, &key_type)?;
+        write_table_cell(&mut tw, &public_key)?;
+        write_table_cell(&mut tw, row.get::<_, Option<String>>(3))?;
+        tw.write_all(b"\n")?;
+    }
+    print_tabwriter(tw)
+}
 
+fn add_user_ssh_key(mut transaction: Transaction, name: &str, key: &str) -> Result<()> {
+    let SshPublicKey { key_type, public_key, comment } = parse_ssh_public_key(key)?;
     transaction.execute(
-        "INSERT INTO machines (hostname, owner, provider_id, provider_reference)
-                VALUES ($1::varchar, $2::varchar, $3, $4)",
-        &[&hostname, &owner, &provider_id, &provider_reference]
+        "INSERT INTO user_ssh_keys (name, key_type, public_key, comment) VALUES ($1::varchar, $2::varchar, $3::text, $4::text)",
+        &[&name, &key_type, &public_key, &comment],
     )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Remove a user's SSH key, given as the whole public key or just its base64 part
+fn remove_user_ssh_key(mut transaction: Transaction, name: &str, key: &str) -> Result<()> {
+    let public_key = match key.trim().contains(char::is_whitespace) {
+        true => parse_ssh_public_key(key)?.public_key,
+        false => key.trim().to_string(),
+    };
+    let num_deleted = transaction.execute("DELETE FROM user_ssh_keys WHERE name = $1 AND public_key = $2", &[&name, &public_key])?;
+    ensure!(num_deleted == 1, "Could not find SSH key for user {:?} in database", name);
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_ssh_grants(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["USER", "MACHINE", "OWNER", "LOGIN"])?;
+    for row in transaction.query("SELECT name, hostname, owner, login FROM ssh_grants ORDER BY (name, hostname, owner, login)", &[])? {
+        let name: String = row.get(0);
+        write_table_cell(&mut tw, &name)?;
+        write_table_cell(&mut tw, row.get::<_, Option<String>>(1))?;
+        write_table_cell(&mut tw, row.get::<_, Option<String>>(2))?;
+        write_table_cell(&mut tw, row.get::<_, Option<String>>(3))?;
+        tw.write_all(b"\n")?;
+    }
+    print_tabwriter(tw)
+}
+
+/// SQL condition matching the grant with parameters $1 through $4: name, hostname, owner, and login
+const SSH_GRANT_CONDITION: &str =
+    "name = $1 AND hostname IS NOT DISTINCT FROM $2::varchar AND owner IS NOT DISTINCT FROM $3::varchar AND login IS NOT DISTINCT FROM $4::varchar";
+
+fn add_ssh_grant(mut transaction: Transaction, name: &str, hostname: Option<&str>, owner: Option<&str>, login: Option<&str>) -> Result<()> {
+    let exists = !transaction.query(&*format!("SELECT 1 FROM ssh_grants WHERE {SSH_GRANT_CONDITION}"), &[&name, &hostname, &owner, &login])?.is_empty();
+    ensure!(!exists, "User {:?} already has this grant", name);
     transaction.execute(
-        "INSERT INTO ssh_servers (hostname, ssh_port, ssh_user)
-                VALUES ($1::varchar, $2::integer, $3::varchar)",
-        &[&hostname, &i32::from(ssh_port), &ssh_user]
+        "INSERT INTO ssh_grants (name, hostname, owner, login) VALUES ($1::varchar, $2::varchar, $3::varchar, $4::varchar)",
+        &[&name, &hostname, &owner, &login],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn remove_ssh_grant(mut transaction: Transaction, name: &str, hostname: Option<&str>, owner: Option<&str>, login: Option<&str>) -> Result<()> {
+    let num_deleted = transaction.execute(&*format!("DELETE FROM ssh_grants WHERE {SSH_GRANT_CONDITION}"), &[&name, &hostname, &owner, &login])?;
+    ensure!(num_deleted == 1, "Could not find this grant for user {:?} in database", name);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Get the authorized_keys lines for each login on a machine, sorted by login
+fn get_authorized_keys(transaction: &mut Transaction, for_machine: &str) -> Result<Vec<(String, Vec<String>)>> {
+    let exists = transaction.query_opt("SELECT 1 FROM machines WHERE hostname = $1", &[&for_machine])?.is_some();
+    ensure!(exists, "Could not find machine {:?} in database", for_machine);
+    let mut authorized_keys: Vec<(String, Vec<String>)> = vec![];
+    for row in transaction.query(
+        "SELECT DISTINCT coalesce(ssh_grants.login, ssh_servers.ssh_user), ssh_grants.name, key_type, public_key, coalesce(comment, ssh_grants.name)
+         FROM machines
+         JOIN ssh_grants ON ssh_grants.hostname = machines.hostname OR ssh_grants.owner = machines.owner
+         JOIN user_ssh_keys ON user_ssh_keys.name = ssh_grants.name
+         LEFT JOIN ssh_servers ON ssh_servers.hostname = machines.hostname
+         WHERE machines.hostname = $1
+         ORDER BY 1, 2, 3, 4", &[&for_machine]
+    )? {
+        let login: Option<String> = row.get(0);
+        let name: String = row.get(1);
+        let login = login.with_context(|| {
+            anyhow!("User {:?} has a grant without a login on {:?}, which has no SSH server to get a login from", name, for_machine)
+        })?;
+        let line = format!("{} {} {}", row.get::<_, String>(2), row.get::<_, String>(3), row.get::<_, String>(4));
+        match authorized_keys.last_mut() {
+            Some((last_login, lines)) if *last_login == login => lines.push(line),
+            _ => authorized_keys.push((login, vec![line])),
+        }
+    }
+    Ok(authorized_keys)
+}
+
+/// Get every login on a machine that a grant gave keys to at some point, from the system-versioning
+/// history of grants, keys, owners, and SSH servers, sorted by login.  Logins that no grant ever
+/// gave keys to are left out, because infrabase does not manage their authorized_keys.
+fn get_previous_logins(transaction: &mut Transaction, for_machine: &str) -> Result<Vec<String>> {
+    let logins = transaction.query(
+        "WITH owners AS (SELECT owner FROM machines WHERE hostname = $1
+                         UNION SELECT owner FROM machines_history WHERE hostname = $1),
+              keyed_users AS (SELECT name FROM user_ssh_keys
+                              UNION SELECT name FROM user_ssh_keys_history),
+              grants AS (SELECT name, hostname, owner, login FROM ssh_grants
+                         UNION SELECT name, hostname, owner, login FROM ssh_grants_history),
+              machine_grants AS (SELECT login FROM grants
+                                 WHERE (hostname = $1 OR owner IN (SELECT owner FROM owners))
+                                 AND name IN (SELECT name FROM keyed_users))
+         SELECT login FROM machine_grants WHERE login IS NOT NULL
+         -- Grants without a login gave keys to the SSH server's user
+         UNION SELECT ssh_user FROM (SELECT ssh_user FROM ssh_servers WHERE hostname = $1
+                                     UNION SELECT ssh_user FROM ssh_servers_history WHERE hostname = $1) AS ssh_users
+               WHERE EXISTS (SELECT 1 FROM machine_grants WHERE login IS NULL)
+         ORDER BY 1", &[&for_machine]
+    )?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect();
+    Ok(logins)
+}
+
+/// Print or write the authorized_keys file for each login on a machine, or output them as a NixOS module
+fn print_authorized_keys(transaction: &mut Transaction, for_machine: &str, nix: bool, output_dir: Option<&Path>) -> Result<()> {
+    let authorized_keys = get_authorized_keys(transaction, for_machine)?;
+    if nix {
+        println!("\
+            # infrabase-generated NixOS module for {for_machine}\n\
+            {{ ... }}:\n\
+            {{\
+        ");
+        for (login, lines) in &authorized_keys {
+            println!("  users.users.{}.openssh.authorizedKeys.keys = {};", nix::attr_name(login), lines.to_nix().render(1));
+        }
+        println!("}}");
+        return Ok(());
+    }
+    for (login, lines) in &authorized_keys {
+        let content = format!("# infrabase-generated authorized_keys for {login}@{for_machine}\n{}\n", lines.join("\n"));
+        match output_dir {
+            Some(dir) => write_file_atomically(&dir.join(login), &content)?,
+            None => println!("{content}"),
+        }
+    }
+    if let Some(dir) = output_dir {
+        // Empty the files of logins whose keys were all revoked, instead of leaving their old keys behind
+        for login in get_previous_logins(transaction, for_machine)? {
+            if !authorized_keys.iter().any(|(l, _)| *l == login) {
+                let content = format!("# infrabase-generated authorized_keys for {login}@{for_machine}\n# No keys\n");
+                write_file_atomically(&dir.join(&login), &content)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list_wireguard_interfaces(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["HOSTNAME", "INTERFACE", "WG IPV4", "WG IPV6", "PORT", "PUBKEY"])?;
+    for row in transaction.query(
+        "SELECT hostname, interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_pubkey
+         FROM wireguard_interfaces ORDER BY (hostname, interface)", &[]
+    )? {
+        let hostname: String = row.get(0);
+        let interface: String = row.get(1);
+        let wireguard_ipv4_address: IpAddr = row.get(2);
+        let wireguard_ipv6_address: IpAddr = row.get(3);
+        let wireguard_port: i32 = row.get(4);
+        let wireguard_pubkey: String = row.get(5);
+        writeln!(tw, "{hostname}\t{interface}\t{wireguard_ipv4_address}\t{wireguard_ipv6_address}\t{wireguard_port}\t{wireguard_pubkey}")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_wireguard_interface(
+    mut transaction: Transaction,
+    hostname: &str,
+    interface: &str,
+    wireguard_ipv4_address: Option<Ipv4Addr>,
+    wireguard_ipv6_address: Option<Ipv6Addr>,
+    wireguard_port: Option<u16>,
+) -> Result<()> {
+    insert_wireguard_interface(&mut transaction, hostname, interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Remove a machine's WireGuard interface along with its own exit route and hubs, unless
+/// other machines use it as their exit node or hub
+fn remove_wireguard_interface(mut transaction: Transaction, hostname: &str, interface: &str) -> Result<()> {
+    let clients = transaction.query(
+        "SELECT source_machine FROM wireguard_exit_routes WHERE exit_machine = $1 AND interface = $2 ORDER BY source_machine", &[&hostname, &interface]
+    )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    ensure!(clients.is_empty(), "Machine {:?} is still used as an exit node on {:?} by {:?}", hostname, interface, clients);
+    let spokes = transaction.query(
+        "SELECT spoke_machine FROM wireguard_hubs WHERE hub_machine = $1 AND interface = $2 ORDER BY spoke_machine", &[&hostname, &interface]
+    )?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    ensure!(spokes.is_empty(), "Machine {:?} is still a hub on {:?} for {:?}", hostname, interface, spokes);
+    transaction.execute("DELETE FROM wireguard_exit_routes WHERE source_machine = $1 AND interface = $2", &[&hostname, &interface])?;
+    transaction.execute("DELETE FROM wireguard_hubs WHERE spoke_machine = $1 AND interface = $2", &[&hostname, &interface])?;
+    let num_deleted = transaction.execute(
+        "DELETE FROM wireguard_interfaces WHERE hostname = $1 AND interface = $2",
+        &[&hostname, &interface],
+    )?;
+    ensure!(num_deleted == 1, "Could not find WireGuard interface ({:?}, {:?}) in database", hostname, interface);
+    transaction.commit()?;
+    Ok(())
+}
+
+fn list_wireguard_overlays(transaction: &mut Transaction) -> Result<()> {
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["INTERFACE", "IPV4 START", "IPV4 END", "IPV6 START", "IPV6 END", "MTU"])?;
+    for row in transaction.query("SELECT interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end, mtu FROM wireguard_overlays ORDER BY interface", &[])? {
+        let interface: String = row.get(0);
+        let ipv4_start: Option<IpAddr> = row.get(1);
+        let ipv4_end: Option<IpAddr> = row.get(2);
+        let ipv6_start: Option<IpAddr> = row.get(3);
+        let ipv6_end: Option<IpAddr> = row.get(4);
+        let mtu: Option<i32> = row.get(5);
+        write_table_cell(&mut tw, interface)?;
+        write_table_cell(&mut tw, ipv4_start)?;
+        write_table_cell(&mut tw, ipv4_end)?;
+        write_table_cell(&mut tw, ipv6_start)?;
+        write_table_cell(&mut tw, ipv6_end)?;
+        write_table_cell(&mut tw, mtu)?;
+        tw.write_all(b"\n")?;
+    }
+    print_tabwriter(tw)
+}
+
+fn add_wireguard_overlay(mut transaction: Transaction, interface: &str, pool: &WireguardPool) -> Result<()> {
+    transaction.execute(
+        "INSERT INTO wireguard_overlays (interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end)
+         VALUES ($1::varchar, $2::inet, $3::inet, $4::inet, $5::inet)
+         ON CONFLICT (interface) DO UPDATE
+         SET ipv4_start = EXCLUDED.ipv4_start, ipv4_end = EXCLUDED.ipv4_end, ipv6_start = EXCLUDED.ipv6_start, ipv6_end = EXCLUDED.ipv6_end",
+        &[&interface, &IpAddr::V4(pool.ipv4_start), &IpAddr::V4(pool.ipv4_end), &IpAddr::V6(pool.ipv6_start), &IpAddr::V6(pool.ipv6_end)],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Set or unset (if `value` is None) a setting on one machine's WireGuard interface
+fn set_wireguard_interface_setting(
+    mut transaction: Transaction,
+    hostname: &str,
+    interface: &str,
+    setting: WireguardSetting,
+    value: Option<&str>,
+) -> Result<()> {
+    let value = value.map(|v| setting.normalize_value(v)).transpose()?;
+    let num_updated = transaction.execute(
+        &*format!("UPDATE wireguard_interfaces SET {} = {} WHERE hostname = $1 AND interface = $2", setting.column(), setting.sql_value("$3")),
+        &[&hostname, &interface, &value],
     )?;
+    ensure!(num_updated == 1, "Machine {:?} does not have WireGuard interface {:?}", hostname, interface);
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Set or unset (if `value` is None) the default for a setting on all WireGuard interfaces with some name
+fn set_wireguard_overlay_setting(mut transaction: Transaction, interface: &str, setting: WireguardSetting, value: Option<&str>) -> Result<()> {
+    let value = value.map(|v| setting.normalize_value(v)).transpose()?;
     transaction.execute(
-        "INSERT INTO wireguard_interfaces (hostname, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, wireguard_privkey, wireguard_pubkey)
-                VALUES ($1::varchar, $2::inet, $3::inet, $4::integer, $5::varchar, $6::varchar)",
-        &[&hostname, &IpAddr::V4(wireguard_ipv4_address), &IpAddr::V6(wireguard_ipv6_address), &i32::from(wireguard_port), &str::from_utf8(&keypair.privkey).unwrap(), &str::from_utf8(&keypair.pubkey).unwrap()]
+        "INSERT INTO wireguard_overlays (interface) VALUES ($1::varchar) ON CONFLICT (interface) DO NOTHING",
+        &[&interface],
+    )?;
+    transaction.execute(
+        &*format!("UPDATE wireguard_overlays SET {} = {} WHERE interface = $1", setting.column(), setting.sql_value("$2")),
+        &[&interface, &value],
     )?;
     transaction.commit()?;
+    Ok(())
+}
 
+fn remove_wireguard_overlay(mut transaction: Transaction, interface: &str) -> Result<()> {
+    let num_deleted = transaction.execute("DELETE FROM wireguard_overlays WHERE interface = $1", &[&interface])?;
+    ensure!(num_deleted == 1, "Could not find WireGuard overlay {:?} in database", interface);
+    transaction.commit()?;
     Ok(())
 }
 
@@ -588,47 +2550,837 @@ fn sort_addresses(addresses: &mut [&MachineAddress]) {
     });
 }
 
-fn print_ssh_config(transaction: &mut Transaction, for_machine: &str) -> Result<()> {
-    let machines_map = get_machines_with_addresses(transaction)?;
-    let source_machine =
-        &machines_map.get(for_machine)
-        .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
-    let network_links_priority_map = get_network_links_priority_map(transaction)?;
-    let machines = get_sorted_machines(&machines_map);
-
-    println!("# infrabase-generated SSH config for {for_machine}\n");
-
-    for machine in machines.into_iter() {
-        let network_to_network = get_network_to_network(&network_links_priority_map, &source_machine.networks, &machine.addresses);
-        let (address, ssh_port) = match network_to_network.get(0) {
-            None => {
-                // We prefer to SSH over the non-WireGuard IP because WireGuard may be down,
-                // but in cases where there is no reachable address, use the WireGuard IP instead.
-                (machine.wireguard_ipv4_address.map(IpAddr::V4), machine.ssh_port)
-            },
-            Some((_, dest_network)) => {
-                let mut desired_addresses: Vec<&MachineAddress> = machine.addresses.iter().filter(|a| a.network == **dest_network).collect();
-                sort_addresses(&mut desired_addresses);
-                let desired_address = desired_addresses[0];
-                (Some(desired_address.address), desired_address.ssh_port)
+/// Get the addresses of `machine` that can be reached from `source_networks`, best first
+fn get_reachable_addresses<'a>(
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    source_networks: &[String],
+    machine: &'a Machine,
+) -> Vec<&'a MachineAddress> {
+    let mut reachable = vec![];
+    let mut seen_networks = HashSet::new();
+    for (_, dest_network) in get_network_to_network(network_links_priority_map, source_networks, &machine.addresses) {
+        if !seen_networks.insert(dest_network.clone()) {
+            continue;
+        }
+        let mut addresses: Vec<&MachineAddress> = machine.addresses.iter().filter(|a| a.network == dest_network).collect();
+        sort_addresses(&mut addresses);
+        reachable.extend(addresses);
+    }
+    reachable
+}
+
+/// Get the ssh_config options for reaching `machine` at an address on `network`.  Machine
+/// options take precedence over network options, which take precedence over owner options.
+fn get_ssh_options<'a>(ssh_options_map: &'a SshOptionsMap, machine: &Machine, network: Option<&str>) -> Vec<&'a (String, String)> {
+    let scopes = [
+        Some((SshOptionScope::Machine, machine.hostname.as_str())),
+        network.map(|network| (SshOptionScope::Network, network)),
+        Some((SshOptionScope::Owner, machine.owner.as_str())),
+    ];
+    let mut seen = HashSet::new();
+    let mut options = vec![];
+    for (scope, name) in scopes.into_iter().flatten() {
+        for option in ssh_options_map.get(&(scope, name.to_string())).into_iter().flatten() {
+            if seen.insert(option.0.to_ascii_lowercase()) {
+                options.push(option);
+            }
+        }
+    }
+    options.sort_by_key(|(option, _)| option.to_ascii_lowercase());
+    options
+}
+
+/// A Host block in an ssh_config
+struct SshHost<'a> {
+    name: String,
+    address: IpAddr,
+    port: i32,
+    /// Network of `address`, or `None` for a WireGuard address
+    network: Option<&'a str>,
+    proxy_jump: Option<&'a str>,
+}
+
+/// Whether a machine with `source_networks` can SSH into `machine` without a bastion
+fn can_ssh_directly(network_links_priority_map: &NetworkLinksPriorityMap, source_networks: &[String], machine: &Machine) -> bool {
+    machine.ssh_port.is_some() &&
+        get_reachable_addresses(network_links_priority_map, source_networks, machine)
+            .first()
+            .is_some_and(|address| address.ssh_port.is_some())
+}
+
+/// Whether `bastion` can SSH into `machine`, over a reachable address or WireGuard, so that
+/// `get_ssh_hosts` has a Host block for `machine` with `bastion` as its ProxyJump
+fn can_ssh_through(network_links_priority_map: &NetworkLinksPriorityMap, bastion: &Machine, machine: &Machine) -> bool {
+    !get_ssh_hosts(network_links_priority_map, &[], false, machine, Some(bastion), false).is_empty()
+}
+
+/// Find the bastion to use as a ProxyJump for each machine that `source_networks` cannot
+/// reach directly.  A bastion is a machine that can be reached, directly or through its own
+/// bastion, and that can reach the machine; bastions with the fewest hops are preferred.
+/// An `ssh-bastion` override is only used if the bastion can reach the machine.
+/// Returns a map of hostname -> bastion, with `None` for machines that are reached directly.
+fn get_ssh_bastions<'a>(
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    ssh_bastion_map: &SshBastionMap,
+    source_networks: &[String],
+    machines: &[&'a Machine],
+) -> HashMap<&'a str, Option<&'a str>> {
+    let mut bastions = HashMap::new();
+    // Machines that can be reached, in order of hops
+    let mut reached = vec![];
+    for machine in machines.iter().filter(|m| can_ssh_directly(network_links_priority_map, source_networks, m)) {
+        bastions.insert(machine.hostname.as_str(), None);
+        reached.push(*machine);
+    }
+    loop {
+        let mut found = vec![];
+        for machine in machines.iter().filter(|m| m.ssh_port.is_some() && !bastions.contains_key(m.hostname.as_str())) {
+            let bastion = match ssh_bastion_map.get(&machine.hostname) {
+                Some(bastion) => reached.iter().find(|b| b.hostname == *bastion && can_ssh_through(network_links_priority_map, b, machine)),
+                None => reached.iter().find(|b| can_ssh_directly(network_links_priority_map, &b.networks, machine)),
+            };
+            if let Some(bastion) = bastion {
+                found.push((*machine, *bastion));
+            }
+        }
+        if found.is_empty() {
+            break;
+        }
+        for (machine, bastion) in found {
+            bastions.insert(machine.hostname.as_str(), Some(bastion.hostname.as_str()));
+            reached.push(machine);
+        }
+    }
+    bastions
+}
+
+/// Get the Host blocks for a machine: one for its hostname and, with `aliases`,
+/// `hostname.wg`, `hostname.v4`, and `hostname.v6` for each way of reaching it.
+/// With a `bastion`, addresses are chosen for the bastion's networks instead of `source_networks`.
+/// WireGuard addresses are only used if the source, or the bastion, is on WireGuard.
+fn get_ssh_hosts<'a>(
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    source_networks: &'a [String],
+    source_has_wireguard: bool,
+    machine: &'a Machine,
+    bastion: Option<&'a Machine>,
+    aliases: bool,
+) -> Vec<SshHost<'a>> {
+    let hostname = &machine.hostname;
+    let (source_networks, proxy_jump, can_use_wireguard) = match bastion {
+        Some(bastion) => (bastion.networks.as_slice(), Some(bastion.hostname.as_str()), bastion.wireguard_ipv4_address.is_some()),
+        None => (source_networks, None, source_has_wireguard),
+    };
+    let reachable = get_reachable_addresses(network_links_priority_map, source_networks, machine);
+    let wireguard_host = |name: String, proxy_jump: Option<&'a str>| {
+        machine.wireguard_ipv4_address.zip(machine.ssh_port).map(|(address, port)| {
+            SshHost { name, address: IpAddr::V4(address), port, network: None, proxy_jump }
+        })
+    };
+    let address_host = |name: String, address: &'a MachineAddress| {
+        address.ssh_port.map(|port| SshHost { name, address: address.address, port, network: Some(address.network.as_str()), proxy_jump })
+    };
+
+    let mut hosts = vec![];
+    hosts.push(match reachable.first() {
+        // We prefer to SSH over the non-WireGuard IP because WireGuard may be down,
+        // but in cases where there is no reachable address, use the WireGuard IP instead.
+        None if can_use_wireguard => wireguard_host(hostname.clone(), proxy_jump),
+        None => None,
+        Some(address) => address_host(hostname.clone(), address),
+    });
+    if aliases && source_has_wireguard {
+        hosts.push(wireguard_host(format!("{hostname}.wg"), None));
+    }
+    if aliases {
+        for (suffix, ipv6) in [("v4", false), ("v6", true)] {
+            if let Some(address) = reachable.iter().find(|a| a.address.is_ipv6() == ipv6) {
+                hosts.push(address_host(format!("{hostname}.{suffix}"), address));
             }
+        }
+    }
+    hosts.into_iter().flatten().collect()
+}
+
+/// Get the networks of `for_machine` if there is one, otherwise check that `from_networks`
+/// exist and return them, for machines or people that are not in the inventory
+fn get_source_networks(
+    transaction: &mut Transaction,
+    machines_map: &MachinesMap,
+    for_machine: Option<&str>,
+    from_networks: &[String],
+) -> Result<Vec<String>> {
+    if let Some(for_machine) = for_machine {
+        let machine = unwrap_or_else!(
+            machines_map.get(for_machine),
+            bail!("Could not find machine {:?} in database", for_machine)
+        );
+        return Ok(machine.networks.clone());
+    }
+    ensure!(!from_networks.is_empty(), "Either a machine or at least one network is required");
+    for network in from_networks {
+        let exists = transaction.query_opt("SELECT 1 FROM networks WHERE name = $1", &[network])?.is_some();
+        ensure!(exists, "Could not find network {:?} in database", network);
+    }
+    Ok(from_networks.to_vec())
+}
+
+/// Whether the machine that config is generated for is on WireGuard; networks given
+/// with --from-network are not, so WireGuard addresses are unreachable from them
+fn source_has_wireguard(machines_map: &MachinesMap, for_machine: Option<&str>) -> bool {
+    for_machine
+        .and_then(|hostname| machines_map.get(hostname))
+        .is_some_and(|machine| machine.wireguard_ipv4_address.is_some())
+}
+
+/// Describe the machine or networks that generated config is for
+fn describe_source(for_machine: Option<&str>, from_networks: &[String]) -> String {
+    match for_machine {
+        Some(for_machine) => for_machine.to_string(),
+        None if from_networks.len() == 1 => format!("network {}", from_networks[0]),
+        None => format!("networks {}", from_networks.join(", ")),
+    }
+}
+
+/// Get the Host blocks that `ssh-config` writes for each machine with an SSH server, sorted by hostname
+fn get_machines_ssh_hosts<'a>(
+    network_links_priority_map: &NetworkLinksPriorityMap,
+    ssh_bastion_map: &SshBastionMap,
+    source_networks: &'a [String],
+    source_has_wireguard: bool,
+    machines_map: &'a MachinesMap,
+    aliases: bool,
+) -> Vec<(&'a Machine, Vec<SshHost<'a>>)> {
+    let machines = get_sorted_machines(machines_map);
+    let bastions = get_ssh_bastions(network_links_priority_map, ssh_bastion_map, source_networks, &machines);
+    let mut machines_hosts = vec![];
+    for machine in machines {
+        if machine.ssh_port.is_none() {
+            // No SSH server
+            continue;
+        }
+        let bastion = match bastions.get(machine.hostname.as_str()) {
+            Some(Some(bastion)) => Some(&machines_map[*bastion]),
+            _ => None,
         };
+        let hosts = get_ssh_hosts(network_links_priority_map, source_networks, source_has_wireguard, machine, bastion, aliases);
+        if hosts.is_empty() {
+            eprintln!("Skipping machine {:?} because it can't be reached directly, over WireGuard, or through a bastion", machine.hostname);
+            continue;
+        }
+        machines_hosts.push((machine, hosts));
+    }
+    machines_hosts
+}
+
+fn print_ssh_config(transaction: &mut Transaction, for_machine: Option<&str>, from_networks: &[String], aliases: bool) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
+    let source_networks = get_source_networks(transaction, &machines_map, for_machine, from_networks)?;
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+    let ssh_options_map = get_ssh_options_map(transaction)?;
+    let ssh_bastion_map = get_ssh_bastion_map(transaction)?;
 
-        if let (Some(address), Some(port)) = (address, ssh_port) {
-            let owner = &machine.owner;
-            let hostname = &machine.hostname;
+    println!("# infrabase-generated SSH config for {}\n", describe_source(for_machine, from_networks));
+
+    for (machine, hosts) in get_machines_ssh_hosts(&network_links_priority_map, &ssh_bastion_map, &source_networks, source_has_wireguard(&machines_map, for_machine), &machines_map, aliases) {
+        let ssh_user = machine.ssh_user.as_deref().unwrap_or_default();
+        let owner = &machine.owner;
+        println!("# owner: {owner}");
+        for SshHost { name, address, port, network, proxy_jump } in hosts {
             let t = "  ";
+            let maybe_proxy_jump = match proxy_jump {
+                Some(bastion) => format!("{t}ProxyJump {bastion}\n"),
+                None => "".to_string(),
+            };
+            let options = get_ssh_options(&ssh_options_map, machine, network)
+                .into_iter()
+                .map(|(option, value)| format!("{t}{option} {value}\n"))
+                .join("");
             println!("\
-                # owner: {owner}\n\
-                Host {hostname}\n\
+                Host {name}\n\
                 {t}HostName {address}\n\
                 {t}Port {port}\n\
+                {t}User {ssh_user}\n\
+                {maybe_proxy_jump}\
+                {options}\
             ");
         }
     }
     Ok(())
 }
 
+/// Format a host for a known_hosts file, which needs the port unless it is 22
+fn format_known_host(host: &str, port: i32) -> String {
+    match port {
+        22 => host.to_string(),
+        port => format!("[{host}]:{port}"),
+    }
+}
+
+/// Output a known_hosts file with the host keys of every machine, under every name
+/// and address:port that `ssh-config --aliases` uses for it
+fn print_known_hosts(transaction: &mut Transaction, for_machine: Option<&str>, from_networks: &[String]) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
+    let source_networks = get_source_networks(transaction, &machines_map, for_machine, from_networks)?;
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+    let ssh_bastion_map = get_ssh_bastion_map(transaction)?;
+    let mut host_keys: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for row in transaction.query("SELECT hostname, key_type, public_key FROM ssh_host_keys ORDER BY (hostname, key_type)", &[])? {
+        host_keys.entry(row.get(0)).or_default().push((row.get(1), row.get(2)));
+    }
+
+    println!("# infrabase-generated known_hosts for {}", describe_source(for_machine, from_networks));
+    for (machine, hosts) in get_machines_ssh_hosts(&network_links_priority_map, &ssh_bastion_map, &source_networks, source_has_wireguard(&machines_map, for_machine), &machines_map, true) {
+        let keys = match host_keys.get(&machine.hostname) {
+            Some(keys) => keys,
+            None => continue,
+        };
+        let names = hosts.iter()
+            .flat_map(|host| [format_known_host(&host.name, host.port), format_known_host(&host.address.to_string(), host.port)])
+            .unique()
+            .join(",");
+        for (key_type, public_key) in keys {
+            println!("{names} {key_type} {public_key}");
+        }
+    }
+    Ok(())
+}
+
+/// The format of `hosts` output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostsFormat {
+    /// An /etc/hosts fragment
+    Hosts,
+    /// unbound local-data entries
+    Unbound,
+    /// dnsmasq host-record entries
+    Dnsmasq,
+}
+
+impl str::FromStr for HostsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hosts" => Ok(HostsFormat::Hosts),
+            "unbound" => Ok(HostsFormat::Unbound),
+            "dnsmasq" => Ok(HostsFormat::Dnsmasq),
+            _ => bail!("Unknown hosts format {:?}, expected hosts, unbound, or dnsmasq", s),
+        }
+    }
+}
+
+/// Get the address to use to reach `machine` from `source_networks`, chosen like `ssh-config`
+/// does: the best reachable address, or the WireGuard IP if there is none and the source is on WireGuard
+fn get_best_address(network_links_priority_map: &NetworkLinksPriorityMap, source_networks: &[String], source_has_wireguard: bool, machine: &Machine) -> Option<IpAddr> {
+    match get_reachable_addresses(network_links_priority_map, source_networks, machine).first() {
+        Some(address) => Some(address.address),
+        None if source_has_wireguard => machine.wireguard_ipv4_address.map(IpAddr::V4),
+        None => None,
+    }
+}
+
+/// Output a name for every other machine, resolving to the address that `for_machine`
+/// or a client on `from_networks` should use to reach it
+fn print_hosts(
+    transaction: &mut Transaction,
+    for_machine: Option<&str>,
+    from_networks: &[String],
+    domain: Option<&str>,
+    format: HostsFormat,
+) -> Result<()> {
+    let domain = domain.map(check_zone_name).transpose()?;
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
+    let source_networks = get_source_networks(transaction, &machines_map, for_machine, from_networks)?;
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+
+    let what = match format {
+        HostsFormat::Hosts => "hosts",
+        HostsFormat::Unbound => "unbound local-data",
+        HostsFormat::Dnsmasq => "dnsmasq host-records",
+    };
+    println!("# infrabase-generated {what} for {}", describe_source(for_machine, from_networks));
+    let mut tw = TabWriter::new(vec![]);
+    for machine in get_sorted_machines(&machines_map) {
+        let hostname = &machine.hostname;
+        if Some(hostname.as_str()) == for_machine {
+            continue;
+        }
+        let address = unwrap_or_else!(
+            get_best_address(&network_links_priority_map, &source_networks, source_has_wireguard(&machines_map, for_machine), machine),
+            continue
+        );
+        // The fully-qualified name comes first because it is the canonical name
+        let names = match domain {
+            Some(domain) => vec![format!("{hostname}.{domain}"), hostname.clone()],
+            None => vec![hostname.clone()],
+        };
+        match format {
+            HostsFormat::Hosts => writeln!(tw, "{address}\t{}", names.join(" "))?,
+            HostsFormat::Unbound => {
+                writeln!(tw, "local-data: \"{}. IN {} {address}\"", names[0], dns_record_type(address))?;
+                writeln!(tw, "local-data-ptr: \"{address} {}.\"", names[0])?;
+            },
+            HostsFormat::Dnsmasq => writeln!(tw, "host-record={},{address}", names.join(","))?,
+        }
+    }
+    print_tabwriter(tw)
+}
+
+/// Whether `label` can be used as a label of a DNS hostname
+fn is_dns_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= 63 &&
+        !label.starts_with('-') && !label.ends_with('-') &&
+        label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Check that `zone` is a valid DNS name and return it without a trailing dot
+fn check_zone_name(zone: &str) -> Result<&str> {
+    let zone = zone.strip_suffix('.').unwrap_or(zone);
+    ensure!(zone.split('.').all(is_dns_label), "Invalid DNS zone name {:?}", zone);
+    Ok(zone)
+}
+
+/// Get a zone serial that changes only when the data in `tables` changes: the Unix time
+/// of the latest row_start in the tables or row_end in their system-versioning history
+fn get_zone_serial(transaction: &mut Transaction, tables: &[&str]) -> Result<u32> {
+    let changes = tables.iter()
+        .map(|table| format!("SELECT max(row_start) AS changed FROM {table} UNION ALL SELECT max(row_end) FROM {table}_history"))
+        .join(" UNION ALL ");
+    let row = transaction.query_one(&*format!("SELECT max(changed) FROM ({changes}) changes"), &[])?;
+    let changed: Option<DateTime<Utc>> = row.get(0);
+    match changed {
+        // Serial 0 is best avoided because some tools treat it as unset
+        None => Ok(1),
+        Some(changed) => u32::try_from(changed.timestamp()).with_context(|| anyhow!("Can't make a zone serial from {}", changed)),
+    }
+}
+
+/// Options for the SOA and NS records at the top of a zone
+#[derive(StructOpt, Debug)]
+struct ZoneOptions {
+    /// Name server to list in the NS records; the first one is also the primary in the SOA record
+    ///
+    /// Names without a trailing dot are relative to the zone.
+    #[structopt(long, value_name = "NAME", required = true, min_values = 1)]
+    nameserver: Vec<String>,
+
+    /// Mailbox of the person responsible for the zone, in SOA format (`.` instead of `@`)
+    #[structopt(long, value_name = "NAME", default_value = "hostmaster")]
+    hostmaster: String,
+
+    /// TTL for all records
+    #[structopt(long, default_value = "3600")]
+    ttl: u32,
+}
+
+/// Format the lines at the top of a zone file, up to and including its NS records
+fn format_zone_header(zone: &str, options: &ZoneOptions, serial: u32) -> String {
+    let ZoneOptions { nameserver: nameservers, hostmaster, ttl } = options;
+    let primary = &nameservers[0];
+    let mut header = format!("\
+        $ORIGIN {zone}.\n\
+        $TTL {ttl}\n\
+        @\tIN\tSOA\t{primary} {hostmaster} {serial} 3600 900 1209600 300\n\
+    ");
+    for nameserver in nameservers {
+        header.push_str(&format!("@\tIN\tNS\t{nameserver}\n"));
+    }
+    header
+}
+
+fn dns_record_type(address: IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+/// Output a zone with records for each machine's WireGuard addresses, at its hostname,
+/// and for each of its other addresses, at hostname.network
+fn print_dns_zone(transaction: &mut Transaction, zone: &str, interface: &str, options: &ZoneOptions) -> Result<()> {
+    let zone = check_zone_name(zone)?;
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    let serial = get_zone_serial(transaction, &["machines", "machine_addresses", "wireguard_interfaces"])?;
+
+    let mut tw = TabWriter::new(vec![]);
+    tw.write_all(format!("; infrabase-generated zone for {zone}\n").as_bytes())?;
+    tw.write_all(format_zone_header(zone, options, serial).as_bytes())?;
+    for machine in get_sorted_machines(&machines_map) {
+        let hostname = &machine.hostname;
+        if !is_dns_label(hostname) {
+            eprintln!("Skipping machine {:?} because its hostname is not a valid DNS label", hostname);
+            continue;
+        }
+        let wireguard_addresses = [
+            machine.wireguard_ipv4_address.map(IpAddr::V4),
+            machine.wireguard_ipv6_address.map(IpAddr::V6),
+        ];
+        for address in wireguard_addresses.into_iter().flatten() {
+            writeln!(tw, "{hostname}\tIN\t{}\t{address}", dns_record_type(address))?;
+        }
+        let mut addresses = machine.addresses.iter().collect::<Vec<_>>();
+        addresses.sort_by_key(|a| (&a.network, a.address.is_ipv6(), a.address));
+        for address in addresses {
+            let network = &address.network;
+            if !is_dns_label(network) {
+                eprintln!("Skipping address {} of machine {:?} because network {:?} is not a valid DNS label", address.address, hostname, network);
+                continue;
+            }
+            writeln!(tw, "{hostname}.{network}\tIN\t{}\t{}", dns_record_type(address.address), address.address)?;
+        }
+    }
+    print_tabwriter(tw)
+}
+
+/// Get the in-addr.arpa or ip6.arpa name of an address
+fn reverse_dns_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        },
+        IpAddr::V6(ip) => {
+            let nibbles = (0..32).map(|i| format!("{:x}", (u128::from(ip) >> (i * 4)) & 0xf)).join(".");
+            format!("{nibbles}.ip6.arpa")
+        },
+    }
+}
+
+/// A reverse DNS zone covering part of a WireGuard pool
+#[derive(Debug, PartialEq, Eq)]
+struct ReverseZone {
+    /// Zone name, without a trailing dot
+    name: String,
+    network: IpAddr,
+    prefix_len: u32,
+    /// For an RFC 2317 zone smaller than a /24, the name of the /24 zone that delegates to it
+    rfc2317_parent: Option<String>,
+}
+
+impl ReverseZone {
+    /// Make the zone for a network whose prefix is a whole number of labels: octets for IPv4, nibbles for IPv6
+    fn aligned(network: IpAddr, prefix_len: u32) -> Self {
+        let bits_per_label = if network.is_ipv4() { 8 } else { 4 };
+        let name = reverse_dns_name(network);
+        let labels = name.split('.').collect::<Vec<_>>();
+        // Drop the labels for the host part, keeping the "in-addr.arpa" or "ip6.arpa" at the end
+        let host_labels = labels.len() - 2 - (prefix_len / bits_per_label) as usize;
+        ReverseZone { name: labels[host_labels..].join("."), network, prefix_len, rfc2317_parent: None }
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = prefix_mask(32, self.prefix_len) as u32;
+                u32::from(address) & mask == u32::from(network)
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                u128::from(address) & prefix_mask(128, self.prefix_len) == u128::from(network)
+            },
+            _ => false,
+        }
+    }
+
+    /// Get the owner name of the PTR record for `address`, relative to the zone
+    fn record_name(&self, address: IpAddr) -> String {
+        let name = reverse_dns_name(address);
+        match self.rfc2317_parent {
+            // Records in an RFC 2317 zone are named after the last octet, below the zone name
+            Some(_) => name.split('.').next().unwrap().to_string(),
+            None => name.strip_suffix(&format!(".{}", self.name)).unwrap().to_string(),
+        }
+    }
+}
+
+/// Get the reverse zones for an IPv4 pool.  Parts of the pool that fit in less than a /24
+/// are given RFC 2317 zones with `rfc2317`, otherwise the whole /24.  Whole /16s and /8s
+/// become one zone.
+fn get_ipv4_reverse_zones(start: Ipv4Addr, end: Ipv4Addr, rfc2317: bool) -> Vec<ReverseZone> {
+    let (start, end) = (u32::from(start), u32::from(end));
+    let mut zones = vec![];
+    // (network, prefix_len) of zones on octet boundaries
+    let mut blocks = vec![];
+    for block in (start >> 8)..=(end >> 8) {
+        let first = start.max(block << 8);
+        let last = end.min(block << 8 | 0xff);
+        // The smallest network containing both `first` and `last`
+        let prefix_len = (first ^ last).leading_zeros();
+        if rfc2317 && prefix_len > 24 {
+            let network = first & prefix_mask(32, prefix_len) as u32;
+            let parent = ReverseZone::aligned(IpAddr::V4(Ipv4Addr::from(block << 8)), 24);
+            zones.push(ReverseZone {
+                name: format!("{}/{}.{}", network & 0xff, prefix_len, parent.name),
+                network: IpAddr::V4(Ipv4Addr::from(network)),
+                prefix_len,
+                rfc2317_parent: Some(parent.name),
+            });
+        } else {
+            blocks.push((block << 8, 24));
+        }
+    }
+    for prefix_len in [16, 8] {
+        let mut children: HashMap<u32, u32> = HashMap::new();
+        for (network, _) in blocks.iter().filter(|(_, p)| *p == prefix_len + 8) {
+            *children.entry(network & prefix_mask(32, prefix_len) as u32).or_default() += 1;
+        }
+        let parents = children.into_iter().filter(|(_, count)| *count == 256).map(|(network, _)| network).collect::<HashSet<_>>();
+        blocks.retain(|(network, p)| !(*p == prefix_len + 8 && parents.contains(&(network & prefix_mask(32, prefix_len) as u32))));
+        blocks.extend(parents.into_iter().map(|network| (network, prefix_len)));
+    }
+    zones.extend(blocks.into_iter().map(|(network, prefix_len)| ReverseZone::aligned(IpAddr::V4(Ipv4Addr::from(network)), prefix_len)));
+    zones.sort_by_key(|zone| (zone.network, zone.prefix_len));
+    zones
+}
+
+/// Get the reverse zone for an IPv6 pool: the smallest zone on a nibble boundary containing it
+fn get_ipv6_reverse_zone(start: Ipv6Addr, end: Ipv6Addr) -> ReverseZone {
+    let (start, end) = (u128::from(start), u128::from(end));
+    let prefix_len = (start ^ end).leading_zeros() / 4 * 4;
+    ReverseZone::aligned(IpAddr::V6(Ipv6Addr::from(start & prefix_mask(128, prefix_len))), prefix_len)
+}
+
+/// Output a reverse zone for each part of `interface`'s WireGuard pool, with PTR records
+/// pointing to the hostnames that `dns-zone` creates in `zone`
+fn print_reverse_dns_zones(
+    transaction: &mut Transaction,
+    zone: &str,
+    interface: &str,
+    rfc2317: bool,
+    options: &ZoneOptions,
+    output_dir: Option<&Path>,
+) -> Result<()> {
+    let zone = check_zone_name(zone)?;
+    let pool = get_wireguard_pool(transaction, interface)?;
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    let serial = get_zone_serial(transaction, &["machines", "wireguard_interfaces", "wireguard_overlays"])?;
+
+    let mut reverse_zones = get_ipv4_reverse_zones(pool.ipv4_start, pool.ipv4_end, rfc2317);
+    reverse_zones.push(get_ipv6_reverse_zone(pool.ipv6_start, pool.ipv6_end));
+    let mut records: Vec<Vec<(IpAddr, &str)>> = reverse_zones.iter().map(|_| vec![]).collect();
+    for machine in get_sorted_machines(&machines_map) {
+        let hostname = &machine.hostname;
+        if !is_dns_label(hostname) {
+            eprintln!("Skipping machine {:?} because its hostname is not a valid DNS label", hostname);
+            continue;
+        }
+        let addresses = [machine.wireguard_ipv4_address.map(IpAddr::V4), machine.wireguard_ipv6_address.map(IpAddr::V6)];
+        for address in addresses.into_iter().flatten() {
+            match reverse_zones.iter().position(|z| z.contains(address)) {
+                Some(idx) => records[idx].push((address, hostname)),
+                None => eprintln!("Skipping address {} of machine {:?} because it is outside the {} pool", address, hostname, interface),
+            }
+        }
+    }
+
+    for (reverse_zone, mut records) in reverse_zones.iter().zip(records) {
+        records.sort();
+        let ReverseZone { name, network, prefix_len, rfc2317_parent } = reverse_zone;
+        let mut tw = TabWriter::new(vec![]);
+        writeln!(tw, "; infrabase-generated reverse zone for {network}/{prefix_len} on {interface}")?;
+        if let Some(parent) = rfc2317_parent {
+            let label = name.strip_suffix(&format!(".{parent}")).unwrap();
+            let first = u32::from(get_ipv4addr(*network)) & 0xff;
+            let last = first + (1 << (32 - prefix_len)) - 1;
+            writeln!(tw, ";\n; The parent zone {parent} must delegate to this one with:")?;
+            for nameserver in &options.nameserver {
+                writeln!(tw, ";   {label} IN NS {nameserver}")?;
+            }
+            writeln!(tw, ";   $GENERATE {first}-{last} $ IN CNAME $.{label}\n;")?;
+        }
+        tw.write_all(format_zone_header(name, options, serial).as_bytes())?;
+        for (address, hostname) in records {
+            writeln!(tw, "{}\tIN\tPTR\t{hostname}.{zone}.", reverse_zone.record_name(address))?;
+        }
+        let content = String::from_utf8(tw.into_inner()?)?;
+        match output_dir {
+            // Names of RFC 2317 zones contain a "/"
+            Some(dir) => write_file_atomically(&dir.join(format!("{}.zone", name.replace('/', "-"))), &content)?,
+            None => println!("{content}"),
+        }
+    }
+    Ok(())
+}
+
+/// Make an Ansible group name, which may only contain letters, digits, and underscores
+fn ansible_group_name(prefix: &str, name: &str) -> String {
+    let name = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>();
+    format!("{prefix}_{name}")
+}
+
+/// What `ansible-inventory` outputs
+#[derive(Debug)]
+enum AnsibleInventoryOutput {
+    /// An inventory file in YAML or JSON
+    Static { json: bool },
+    /// The JSON a dynamic inventory script outputs for `--list`
+    List,
+    /// The JSON a dynamic inventory script outputs for `--host HOST`
+    Host(String),
+}
+
+/// Output an Ansible inventory of every machine that `ssh-config` has a Host for, connecting
+/// the same way.  Machines are grouped by owner, provider, and network.
+fn print_ansible_inventory(
+    transaction: &mut Transaction,
+    for_machine: Option<&str>,
+    from_networks: &[String],
+    output: AnsibleInventoryOutput,
+) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, DEFAULT_WIREGUARD_INTERFACE)?;
+    let source_networks = get_source_networks(transaction, &machines_map, for_machine, from_networks)?;
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+    let ssh_bastion_map = get_ssh_bastion_map(transaction)?;
+    let provider_names = get_provider_names(transaction)?;
+
+    let machines_hosts = get_machines_ssh_hosts(&network_links_priority_map, &ssh_bastion_map, &source_networks, source_has_wireguard(&machines_map, for_machine), &machines_map, false);
+    let first_hosts = machines_hosts.iter()
+        .map(|(machine, hosts)| (machine.hostname.as_str(), &hosts[0]))
+        .collect::<HashMap<_, _>>();
+    let mut hostvars = serde_json::Map::new();
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (machine, hosts) in &machines_hosts {
+        let hostname = machine.hostname.as_str();
+        let SshHost { address, port, proxy_jump, .. } = &hosts[0];
+        let mut vars = serde_json::Map::new();
+        vars.insert("ansible_host".into(), address.to_string().into());
+        vars.insert("ansible_port".into(), (*port).into());
+        vars.insert("ansible_user".into(), machine.ssh_user.clone().into());
+        if proxy_jump.is_some() {
+            // ssh -J takes the first hop first
+            let mut jumps = vec![];
+            let mut bastion = *proxy_jump;
+            while let Some(hostname) = bastion {
+                let host = unwrap_or_else!(
+                    first_hosts.get(hostname),
+                    bail!("Bastion {:?} of machine {:?} can't be reached", hostname, machine.hostname)
+                );
+                let user = machines_map[hostname].ssh_user.as_deref().unwrap_or_default();
+                let port = u16::try_from(host.port)
+                    .with_context(|| anyhow!("Port {} out of expected range 0-65535", host.port))?;
+                jumps.push(format!("{user}@{}", SocketAddr::new(host.address, port)));
+                bastion = host.proxy_jump;
+            }
+            jumps.reverse();
+            vars.insert("ansible_ssh_common_args".into(), format!("-J {}", jumps.join(",")).into());
+        }
+        vars.insert("infrabase_owner".into(), machine.owner.clone().into());
+        let provider = machine.provider_id.and_then(|id| provider_names.get(&id));
+        if let Some(provider) = provider {
+            vars.insert("infrabase_provider".into(), provider.clone().into());
+        }
+        if let Some(provider_reference) = &machine.provider_reference {
+            vars.insert("infrabase_provider_reference".into(), provider_reference.clone().into());
+        }
+        vars.insert("infrabase_networks".into(), machine.networks.iter().sorted().unique().cloned().collect::<Vec<_>>().into());
+        if let Some(address) = machine.wireguard_ipv4_address {
+            vars.insert("infrabase_wireguard_ipv4_address".into(), address.to_string().into());
+        }
+        if let Some(address) = machine.wireguard_ipv6_address {
+            vars.insert("infrabase_wireguard_ipv6_address".into(), address.to_string().into());
+        }
+        hostvars.insert(hostname.to_string(), vars.into());
+
+        groups.entry(ansible_group_name("owner", &machine.owner)).or_default().push(hostname);
+        if let Some(provider) = provider {
+            groups.entry(ansible_group_name("provider", provider)).or_default().push(hostname);
+        }
+        for network in machine.networks.iter().unique() {
+            groups.entry(ansible_group_name("network", network)).or_default().push(hostname);
+        }
+    }
+
+    let inventory = match output {
+        AnsibleInventoryOutput::Static { json } => {
+            let children = groups.into_iter()
+                .map(|(group, hosts)| (group, serde_json::json!({ "hosts": hosts.into_iter().map(|h| (h, ())).collect::<BTreeMap<_, _>>() })))
+                .collect::<BTreeMap<_, _>>();
+            let inventory = serde_json::json!({ "all": { "hosts": hostvars, "children": children } });
+            if !json {
+                print!("# infrabase-generated Ansible inventory for {}\n{}", describe_source(for_machine, from_networks), serde_yaml::to_string(&inventory)?);
+                return Ok(());
+            }
+            inventory
+        },
+        AnsibleInventoryOutput::List => {
+            let mut inventory = serde_json::json!({
+                "_meta": { "hostvars": hostvars },
+                "all": { "children": groups.keys().collect::<Vec<_>>() },
+            });
+            for (group, hosts) in groups {
+                inventory[group] = serde_json::json!({ "hosts": hosts });
+            }
+            inventory
+        },
+        AnsibleInventoryOutput::Host(hostname) => {
+            unwrap_or_else!(
+                hostvars.remove(&hostname),
+                bail!("Machine {:?} is not in the inventory because it does not exist or has no SSH server", hostname)
+            )
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&inventory)?);
+    Ok(())
+}
+
+/// Output a Prometheus file_sd_configs target group for each machine, at its WireGuard IPv4
+/// address or, with `best_address`, the address `ssh-config` on `for_machine` would use
+fn print_prometheus_sd(
+    transaction: &mut Transaction,
+    for_machine: Option<&str>,
+    from_networks: &[String],
+    interface: &str,
+    port: u16,
+    best_address: bool,
+    output: Option<&Path>,
+) -> Result<()> {
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    // The source only matters when picking the best address
+    let source_networks = match best_address {
+        true => get_source_networks(transaction, &machines_map, for_machine, from_networks)?,
+        false => vec![],
+    };
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+    let provider_names = get_provider_names(transaction)?;
+
+    let mut target_groups = vec![];
+    for machine in get_sorted_machines(&machines_map) {
+        let address = match best_address {
+            true => get_best_address(&network_links_priority_map, &source_networks, source_has_wireguard(&machines_map, for_machine), machine),
+            false => machine.wireguard_ipv4_address.map(IpAddr::V4),
+        };
+        let address = unwrap_or_else!(address, {
+            match best_address {
+                true => eprintln!("Skipping machine {:?} because it does not have an address {} can reach", machine.hostname, describe_source(for_machine, from_networks)),
+                false => eprintln!("Skipping machine {:?} because it does not have WireGuard interface {:?}", machine.hostname, interface),
+            }
+            continue;
+        });
+        let mut labels = serde_json::Map::new();
+        labels.insert("hostname".into(), machine.hostname.clone().into());
+        labels.insert("owner".into(), machine.owner.clone().into());
+        if let Some(provider) = machine.provider_id.and_then(|id| provider_names.get(&id)) {
+            labels.insert("provider".into(), provider.clone().into());
+        }
+        if let Some(provider_reference) = &machine.provider_reference {
+            labels.insert("provider_reference".into(), provider_reference.clone().into());
+        }
+        // Label values must be strings
+        labels.insert("networks".into(), machine.networks.iter().sorted().unique().join(",").into());
+        target_groups.push(serde_json::json!({
+            "targets": [SocketAddr::new(address, port).to_string()],
+            "labels": labels,
+        }));
+    }
+    let content = format!("{}\n", serde_json::to_string_pretty(&target_groups)?);
+    match output {
+        // Prometheus watches the file, so it must never see a partially-written one
+        Some(path) => write_file_atomically(path, &content)?,
+        None => print!("{content}"),
+    }
+    Ok(())
+}
+
 struct WireguardPeer {
     hostname: String,
     wireguard_pubkey: String,
@@ -636,6 +3388,70 @@ struct WireguardPeer {
     wireguard_ipv6_address: Ipv6Addr,
     endpoint: Option<(IpAddr, u16)>,
     keepalive: Option<i32>,
+    /// Whether all traffic should be routed through this peer
+    default_route: bool,
+    /// WireGuard addresses of other machines that are reached through this peer
+    relayed_addresses: Vec<(Ipv4Addr, Ipv6Addr)>,
+}
+
+/// Get the AllowedIPs for a peer. For an exit node, this is everything except
+/// its own endpoint, which must still be reached outside the tunnel.
+fn get_peer_allowed_ips(peer: &WireguardPeer) -> Vec<String> {
+    if !peer.default_route {
+        return iter::once((peer.wireguard_ipv4_address, peer.wireguard_ipv6_address))
+            .chain(peer.relayed_addresses.iter().copied())
+            .flat_map(|(ipv4_address, ipv6_address)| [format!("{ipv4_address}/32"), format!("{ipv6_address}/128")])
+            .collect();
+    }
+    let mut allowed_ips = vec![];
+    match peer.endpoint {
+        Some((address @ IpAddr::V4(_), _)) => {
+            allowed_ips.extend(default_route_excluding(address).into_iter().map(|(ip, len)| format!("{ip}/{len}")));
+            allowed_ips.push("::/0".to_string());
+        },
+        Some((address @ IpAddr::V6(_), _)) => {
+            allowed_ips.push("0.0.0.0/0".to_string());
+            allowed_ips.extend(default_route_excluding(address).into_iter().map(|(ip, len)| format!("{ip}/{len}")));
+        },
+        None => {
+            allowed_ips.push("0.0.0.0/0".to_string());
+            allowed_ips.push("::/0".to_string());
+        },
+    }
+    allowed_ips
+}
+
+/// Get the hub a spoke is reached through from mesh machines that are not one of its hubs
+fn get_primary_hub<'a>(hubs_map: &'a WireguardHubsMap, spoke: &str) -> Option<&'a String> {
+    hubs_map.get(spoke).and_then(|hubs| hubs.first())
+}
+
+/// Whether `source` should have `target` as a direct WireGuard peer
+fn is_wireguard_neighbor(hubs_map: &WireguardHubsMap, source: &Machine, target: &Machine) -> bool {
+    let is_hub_of = |hub: &Machine, spoke: &Machine| {
+        hubs_map.get(&spoke.hostname).is_some_and(|hubs| hubs.contains(&hub.hostname))
+    };
+    match (source.wireguard_role, target.wireguard_role) {
+        (WireguardRole::Mesh, WireguardRole::Mesh) => true,
+        (WireguardRole::Mesh, WireguardRole::Spoke) => is_hub_of(source, target),
+        (WireguardRole::Spoke, _) => is_hub_of(target, source),
+    }
+}
+
+/// Whether `source` reaches `target` by sending its traffic to `hub`
+fn is_relayed_through(hubs_map: &WireguardHubsMap, source: &Machine, hub: &Machine, target: &Machine) -> bool {
+    if is_wireguard_neighbor(hubs_map, source, target) {
+        return false;
+    }
+    match source.wireguard_role {
+        // A spoke sends everything it can't reach directly to its primary hub
+        WireguardRole::Spoke => get_primary_hub(hubs_map, &source.hostname) == Some(&hub.hostname),
+        // Mesh machines reach every mesh machine directly, so only spokes need relaying
+        WireguardRole::Mesh => {
+            target.wireguard_role == WireguardRole::Spoke &&
+                get_primary_hub(hubs_map, &target.hostname) == Some(&hub.hostname)
+        },
+    }
 }
 
 /// Get a list of WireGuard peers for a machine, taking into account the source
@@ -645,25 +3461,40 @@ fn get_wireguard_peers(
     machines_map: &MachinesMap,
     network_links_priority_map: &NetworkLinksPriorityMap,
     keepalives_map: &WireguardKeepaliveIntervalMap,
+    exit_route_map: &WireguardExitRouteMap,
+    hubs_map: &WireguardHubsMap,
+    interface: &str,
     for_machine: &str,
 ) -> Result<Vec<WireguardPeer>> {
     let mut peers = vec![];
     let source_machine =
         &machines_map.get(for_machine)
         .ok_or_else(|| anyhow!("machines_map missing {}", for_machine))?;
+    let sorted_machines = get_sorted_machines(machines_map);
     for machine in machines_map.values() {
         if machine.hostname == for_machine {
             // We don't need a [Peer] for ourselves
             continue;
         }
+        if !is_wireguard_neighbor(hubs_map, source_machine, machine) {
+            // Reached through a hub, if at all
+            continue;
+        }
         let network_to_network = get_network_to_network(network_links_priority_map, &source_machine.networks, &machine.addresses);
         let endpoint = match network_to_network.get(0) {
             Some((_, dest_network)) => {
                 let desired_address = machine.addresses.iter().find(|a| a.network == *dest_network);
-                match desired_address {
-                    Some(MachineAddress { address, wireguard_port: Some(port), .. }) => {
-                        Some((*address, u16::try_from(*port)
-                            .with_context(|| anyhow!("Port {} out of expected range 0-65535", *port))?))
+                // An address's WireGuard port, which may be forwarded through a NAT, is only
+                // for the default interface; other interfaces listen on their own port.
+                let port = match desired_address {
+                    Some(address) if interface == DEFAULT_WIREGUARD_INTERFACE => address.wireguard_port,
+                    Some(_) => machine.wireguard_port,
+                    None => None,
+                };
+                match (desired_address, port) {
+                    (Some(MachineAddress { address, .. }), Some(port)) => {
+                        Some((*address, u16::try_from(port)
+                            .with_context(|| anyhow!("Port {} out of expected range 0-65535", port))?))
                     },
                     _ => None,
                 }
@@ -676,6 +3507,12 @@ fn get_wireguard_peers(
                 Some(wireguard_ipv6_address),
                 Some(wireguard_pubkey)) = (machine.wireguard_ipv4_address, machine.wireguard_ipv6_address, &machine.wireguard_pubkey) {
             let keepalive = keepalives_map.get(&(for_machine.to_string(), machine.hostname.to_string())).copied();
+            let default_route = exit_route_map.get(for_machine) == Some(&machine.hostname);
+            let relayed_addresses = sorted_machines
+                .iter()
+                .filter(|m| m.hostname != for_machine && is_relayed_through(hubs_map, source_machine, machine, m))
+                .filter_map(|m| m.wireguard_ipv4_address.zip(m.wireguard_ipv6_address))
+                .collect();
             peers.push(WireguardPeer {
                 hostname: machine.hostname.clone(),
                 wireguard_pubkey: wireguard_pubkey.clone(),
@@ -683,6 +3520,8 @@ fn get_wireguard_peers(
                 wireguard_ipv6_address,
                 endpoint,
                 keepalive,
+                default_route,
+                relayed_addresses,
             });
         }
     }
@@ -697,35 +3536,66 @@ fn sort_wireguard_peers(peers: &mut [WireguardPeer]) {
     });
 }
 
-fn print_wg_quick(transaction: &mut Transaction, for_machine: &str) -> Result<()> {
-    let machines_map = get_machines_with_addresses(transaction)?;
+/// Format the optional [Interface] lines for a wg-quick config
+fn format_wg_quick_settings(settings: &WireguardSettings) -> String {
+    let mut lines = String::new();
+    if let Some(mtu) = settings.mtu {
+        lines.push_str(&format!("MTU = {mtu}\n"));
+    }
+    if !settings.dns.is_empty() {
+        lines.push_str(&format!("DNS = {}\n", settings.dns.join(", ")));
+    }
+    if let Some(route_table) = &settings.route_table {
+        lines.push_str(&format!("Table = {route_table}\n"));
+    }
+    if let Some(fwmark) = settings.fwmark {
+        lines.push_str(&format!("FwMark = {fwmark:#x}\n"));
+    }
+    for (hook, command) in settings.hooks() {
+        lines.push_str(&format!("{} = {command}\n", hook.wg_quick_key()));
+    }
+    lines
+}
+
+/// Get a machine with its WireGuard fields for `interface`, and its sorted
+/// peers on that interface
+fn get_machine_and_wireguard_peers(transaction: &mut Transaction, for_machine: &str, interface: &str) -> Result<(Machine, Vec<WireguardPeer>)> {
+    let mut machines_map = get_machines_with_addresses(transaction, interface)?;
     let network_links_priority_map = get_network_links_priority_map(transaction)?;
     let keepalives_map = get_wireguard_keepalive_map(transaction)?;
+    let exit_route_map = get_wireguard_exit_route_map(transaction, interface)?;
+    let hubs_map = get_wireguard_hubs_map(transaction, interface)?;
     let my_machine = unwrap_or_else!(
         machines_map.get(for_machine),
         bail!("Could not find machine {:?} in database", for_machine)
     );
 
-    ensure!(my_machine.wireguard_ipv4_address.is_some(), "Machine {:?} does not have WireGuard IPv4 address", for_machine);
-    ensure!(my_machine.wireguard_ipv6_address.is_some(), "Machine {:?} does not have WireGuard IPv6 address", for_machine);
+    my_machine.wireguard(interface)?;
+
+    let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, &exit_route_map, &hubs_map, interface, for_machine)?;
+    sort_wireguard_peers(&mut peers);
+    let my_machine = machines_map.remove(for_machine).expect("checked above");
+    Ok((my_machine, peers))
+}
+
+fn print_wg_quick(transaction: &mut Transaction, for_machine: &str, interface: &str) -> Result<()> {
+    let (my_machine, peers) = get_machine_and_wireguard_peers(transaction, for_machine, interface)?;
 
     {
-        let privkey = my_machine.wireguard_privkey.as_ref().unwrap();
-        let my_ipv4_address = &my_machine.wireguard_ipv4_address.unwrap();
-        let my_ipv6_address = &my_machine.wireguard_ipv6_address.unwrap();
-        let listen_port = &my_machine.wireguard_port.unwrap();
+        let MachineWireguard { ipv4_address: my_ipv4_address, ipv6_address: my_ipv6_address, port: listen_port, privkey, .. } =
+            my_machine.wireguard(interface)?;
+        let settings = format_wg_quick_settings(&my_machine.wireguard_settings);
         println!("\
-            # infrabase-generated wg-quick config for {for_machine}\n\
+            # infrabase-generated wg-quick config for {for_machine} {interface}\n\
             \n\
             [Interface]\n\
             Address = {my_ipv4_address}/32, {my_ipv6_address}/128\n\
             PrivateKey = {privkey}\n\
             ListenPort = {listen_port}\n\
+            {settings}\
         ");
     }
 
-    let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, for_machine)?;
-    sort_wireguard_peers(&mut peers);
     for peer in peers {
         let maybe_endpoint = match peer.endpoint {
             Some((address, port)) => format!("Endpoint = {address}:{port}\n"),
@@ -738,13 +3608,12 @@ fn print_wg_quick(transaction: &mut Transaction, for_machine: &str) -> Result<()
         {
             let peer_hostname = &peer.hostname;
             let peer_pubkey = &peer.wireguard_pubkey;
-            let peer_ipv4_address = &peer.wireguard_ipv4_address;
-            let peer_ipv6_address = &peer.wireguard_ipv6_address;
+            let allowed_ips = get_peer_allowed_ips(&peer).join(", ");
             println!("\
                 # {peer_hostname}\n\
                 [Peer]\n\
                 PublicKey = {peer_pubkey}\n\
-                AllowedIPs = {peer_ipv4_address}/32, {peer_ipv6_address}/128\n\
+                AllowedIPs = {allowed_ips}\n\
                 {maybe_endpoint}\
                 {maybe_keepalive}\
             ");
@@ -753,48 +3622,550 @@ fn print_wg_quick(transaction: &mut Transaction, for_machine: &str) -> Result<()
     Ok(())
 }
 
-/// Write a .nix file for each machine listing its WireGuard peers
-fn write_wireguard_peers(transaction: &mut Transaction, with_names: bool) -> Result<()> {
-    let machines_map = get_machines_with_addresses(transaction)?;
-    let network_links_priority_map = get_network_links_priority_map(transaction)?;
-    let keepalives_map = get_wireguard_keepalive_map(transaction)?;
-    let machines = get_sorted_machines(&machines_map);
+/// Format a systemd-networkd .netdev file for a machine's WireGuard interface
+fn format_networkd_netdev(machine: &Machine, interface: &str, peers: &[WireguardPeer]) -> Result<String> {
+    let hostname = &machine.hostname;
+    let settings = &machine.wireguard_settings;
+    let MachineWireguard { port: listen_port, privkey, .. } = machine.wireguard(interface)?;
+    let maybe_mtu = match settings.mtu {
+        Some(mtu) => format!("MTUBytes={mtu}\n"),
+        None => "".to_string(),
+    };
+    let maybe_fwmark = match settings.fwmark {
+        Some(fwmark) => format!("FirewallMark={fwmark:#x}\n"),
+        None => "".to_string(),
+    };
+    // wg-quick adds routes for AllowedIPs to the main table unless Table = off,
+    // while systemd-networkd adds no routes unless RouteTable= is set.
+    let maybe_route_table = match settings.route_table.as_deref() {
+        Some("off") => "".to_string(),
+        None | Some("auto") => "RouteTable=main\n".to_string(),
+        Some(route_table) => format!("RouteTable={route_table}\n"),
+    };
+    let mut netdev = format!("\
+        # infrabase-generated systemd-networkd config for {hostname} {interface}\n\
+        \n\
+        [NetDev]\n\
+        Name={interface}\n\
+        Kind=wireguard\n\
+        {maybe_mtu}\
+        \n\
+        [WireGuard]\n\
+        PrivateKey={privkey}\n\
+        ListenPort={listen_port}\n\
+        {maybe_fwmark}\
+        {maybe_route_table}\
+    ");
+    for peer in peers {
+        let maybe_endpoint = match peer.endpoint {
+            Some((address, port)) => format!("Endpoint={}\n", SocketAddr::new(address, port)),
+            None => "".to_string(),
+        };
+        let maybe_keepalive = match peer.keepalive {
+            Some(interval) => format!("PersistentKeepalive={interval}\n"),
+            None => "".to_string()
+        };
+        let peer_hostname = &peer.hostname;
+        let peer_pubkey = &peer.wireguard_pubkey;
+        let allowed_ips = get_peer_allowed_ips(peer).join(",");
+        netdev.push_str(&format!("\
+            \n\
+            # {peer_hostname}\n\
+            [WireGuardPeer]\n\
+            PublicKey={peer_pubkey}\n\
+            AllowedIPs={allowed_ips}\n\
+            {maybe_endpoint}\
+            {maybe_keepalive}\
+        "));
+    }
+    Ok(netdev)
+}
+
+/// Format a systemd-networkd .network file for a machine's WireGuard interface
+fn format_networkd_network(machine: &Machine, interface: &str) -> Result<String> {
+    let hostname = &machine.hostname;
+    let MachineWireguard { ipv4_address: my_ipv4_address, ipv6_address: my_ipv6_address, .. } = machine.wireguard(interface)?;
+    // wg-quick's DNS = takes both servers and search domains, but networkd wants them separately
+    let (servers, domains): (Vec<&String>, Vec<&String>) = machine.wireguard_settings.dns
+        .iter()
+        .partition(|entry| entry.parse::<IpAddr>().is_ok());
+    let maybe_dns = servers.iter().map(|server| format!("DNS={server}\n")).join("");
+    let maybe_domains = match domains.is_empty() {
+        true => "".to_string(),
+        false => format!("Domains={}\n", domains.iter().join(" ")),
+    };
+    Ok(format!("\
+        # infrabase-generated systemd-networkd config for {hostname} {interface}\n\
+        \n\
+        [Match]\n\
+        Name={interface}\n\
+        \n\
+        [Network]\n\
+        Address={my_ipv4_address}/32\n\
+        Address={my_ipv6_address}/128\n\
+        {maybe_dns}\
+        {maybe_domains}\
+    "))
+}
+
+/// The group systemd-networkd reads its configuration as
+const NETWORKD_GROUP: &str = "systemd-network";
+
+/// Print or write systemd-networkd .netdev and .network files for a machine's WireGuard interface
+fn print_networkd(transaction: &mut Transaction, for_machine: &str, interface: &str, output_dir: Option<&Path>) -> Result<()> {
+    let (my_machine, peers) = get_machine_and_wireguard_peers(transaction, for_machine, interface)?;
+    let settings = &my_machine.wireguard_settings;
+    for (hook, _) in settings.hooks() {
+        eprintln!("Warning: systemd-networkd does not support {}, not including it in the config for {for_machine} {interface}", hook.wg_quick_key());
+    }
+
+    let netdev_name = format!("25-{interface}.netdev");
+    let network_name = format!("25-{interface}.network");
+    let netdev = format_networkd_netdev(&my_machine, interface, &peers)?;
+    let network = format_networkd_network(&my_machine, interface)?;
+    match output_dir {
+        Some(dir) => {
+            // The .netdev contains the private key, so it must not be world-readable,
+            // but systemd-networkd needs to read it through the systemd-network group.
+            let netdev_path = dir.join(&netdev_name);
+            let group_id = get_group_id(NETWORKD_GROUP)?;
+            write_file_atomically_with(&netdev_path, &netdev, |file| {
+                file.set_permissions(fs::Permissions::from_mode(0o640))?;
+                match group_id {
+                    Some(gid) => {
+                        if let Err(err) = std::os::unix::fs::fchown(file, None, Some(gid)) {
+                            eprintln!("Warning: could not change the group of {:?} to {NETWORKD_GROUP}, \
+                                       systemd-networkd will not be able to read it: {err}", netdev_path);
+                        }
+                    },
+                    None => {
+                        eprintln!("Warning: group {NETWORKD_GROUP} does not exist, \
+                                   systemd-networkd will not be able to read {:?}", netdev_path);
+                    },
+                }
+                Ok(())
+            })?;
+            write_file_atomically(&dir.join(&network_name), &network)?;
+        },
+        None => {
+            println!("# {netdev_name}\n{netdev}\n# {network_name}\n{network}");
+        },
+    }
+    Ok(())
+}
 
+/// Output a NetworkManager .nmconnection keyfile for a machine's WireGuard interface
+fn print_nmconnection(transaction: &mut Transaction, for_machine: &str, interface: &str) -> Result<()> {
+    let (my_machine, peers) = get_machine_and_wireguard_peers(transaction, for_machine, interface)?;
+    let settings = &my_machine.wireguard_settings;
+    for (hook, _) in settings.hooks() {
+        eprintln!("Warning: NetworkManager does not support {}, not including it in the config for {for_machine} {interface}", hook.wg_quick_key());
+    }
+
+    let MachineWireguard { ipv4_address: my_ipv4_address, ipv6_address: my_ipv6_address, port: listen_port, privkey, .. } =
+        my_machine.wireguard(interface)?;
+    let maybe_mtu = match settings.mtu {
+        Some(mtu) => format!("mtu={mtu}\n"),
+        None => "".to_string(),
+    };
+    let maybe_fwmark = match settings.fwmark {
+        Some(fwmark) => format!("fwmark={fwmark}\n"),
+        None => "".to_string(),
+    };
+    // NetworkManager adds routes for AllowedIPs to the main table by default, like wg-quick
+    let (peer_routes, maybe_route_table) = match settings.route_table.as_deref() {
+        Some("off") => (false, "".to_string()),
+        None | Some("auto") | Some("main") => (true, "".to_string()),
+        Some(route_table) if route_table.parse::<u32>().is_ok() => (true, format!("route-table={route_table}\n")),
+        Some(route_table) => {
+            eprintln!("Warning: NetworkManager only supports numbered routing tables, not including Table {route_table} in the config for {for_machine} {interface}");
+            (true, "".to_string())
+        },
+    };
+    let (servers, domains): (Vec<&String>, Vec<&String>) = settings.dns
+        .iter()
+        .partition(|entry| entry.parse::<IpAddr>().is_ok());
+    let format_dns = |ipv6: bool| {
+        let family_servers = servers.iter()
+            .filter(|server| server.parse::<IpAddr>().unwrap().is_ipv6() == ipv6)
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+        if !family_servers.is_empty() {
+            out.push_str(&format!("dns={};\n", family_servers.iter().join(";")));
+        }
+        if !domains.is_empty() {
+            out.push_str(&format!("dns-search={};\n", domains.iter().join(";")));
+        }
+        out
+    };
+    let ipv4_dns = format_dns(false);
+    let ipv6_dns = format_dns(true);
+
+    println!("\
+        # infrabase-generated NetworkManager keyfile for {for_machine} {interface}\n\
+        # NetworkManager ignores keyfiles that are not owned by root with mode 0600\n\
+        \n\
+        [connection]\n\
+        id={interface}\n\
+        type=wireguard\n\
+        interface-name={interface}\n\
+        \n\
+        [wireguard]\n\
+        private-key={privkey}\n\
+        listen-port={listen_port}\n\
+        peer-routes={peer_routes}\n\
+        {maybe_mtu}\
+        {maybe_fwmark}\
+    ");
+
+    for peer in peers {
+        let maybe_endpoint = match peer.endpoint {
+            Some((address, port)) => format!("endpoint={}\n", SocketAddr::new(address, port)),
+            None => "".to_string(),
+        };
+        let maybe_keepalive = match peer.keepalive {
+            Some(interval) => format!("persistent-keepalive={interval}\n"),
+            None => "".to_string()
+        };
+        let peer_hostname = &peer.hostname;
+        let peer_pubkey = &peer.wireguard_pubkey;
+        let allowed_ips = get_peer_allowed_ips(&peer).join(";");
+        println!("\
+            # {peer_hostname}\n\
+            [wireguard-peer.{peer_pubkey}]\n\
+            allowed-ips={allowed_ips};\n\
+            {maybe_endpoint}\
+            {maybe_keepalive}\
+        ");
+    }
+
+    print!("\
+        [ipv4]\n\
+        method=manual\n\
+        address1={my_ipv4_address}/32\n\
+        {ipv4_dns}\
+        {maybe_route_table}\
+        \n\
+        [ipv6]\n\
+        method=manual\n\
+        addr-gen-mode=stable-privacy\n\
+        address1={my_ipv6_address}/128\n\
+        {ipv6_dns}\
+        {maybe_route_table}\
+    ");
+    Ok(())
+}
+
+/// Write a .nix file for each machine and WireGuard interface listing its WireGuard peers,
+/// for the machines in `only`, or for all machines if it is empty
+fn write_wireguard_peers(transaction: &mut Transaction, with_names: bool, dry_run: bool, diff: bool, remove_stale: bool, only: &[String]) -> Result<()> {
     let path_template = env_var("WIREGUARD_PEERS_PATH_TEMPLATE")?;
+    for hostname in only {
+        let exists = transaction.query_opt("SELECT 1 FROM machines WHERE hostname = $1", &[hostname])?.is_some();
+        ensure!(exists, "Could not find machine {:?} in database", hostname);
+    }
+    let without_wireguard = transaction.query(
+        "SELECT hostname FROM machines WHERE hostname NOT IN (SELECT hostname FROM wireguard_interfaces) ORDER BY hostname", &[]
+    )?;
+    for row in without_wireguard {
+        let hostname: String = row.get(0);
+        if only.is_empty() || only.contains(&hostname) {
+            eprintln!("Skipping machine {:?} because it does not have a WireGuard interface", hostname);
+        }
+    }
+    let interfaces = transaction.query("SELECT DISTINCT interface FROM wireguard_interfaces ORDER BY interface", &[])?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    ensure!(interfaces.len() <= 1 || path_template.contains("{interface}"),
+            "WIREGUARD_PEERS_PATH_TEMPLATE must contain {{interface}} when there is more than one WireGuard interface");
+    let mut files = vec![];
+    for interface in &interfaces {
+        files.extend(render_wireguard_peers_for_interface(transaction, &path_template, interface, with_names)?);
+    }
+    let peers_dir = get_wireguard_peers_dir(&path_template);
+    let manifest = match &peers_dir {
+        Some(dir) => read_wireguard_peers_manifest(dir)?,
+        None => BTreeSet::new(),
+    };
+    // Files for the other machines are still rendered so that they are not considered stale
+    let rendered_names = files.iter()
+        .filter_map(|file| Path::new(&file.path).file_name()?.to_str().map(String::from))
+        .collect::<BTreeSet<_>>();
+    let stale_paths = match (&peers_dir, only.is_empty()) {
+        (Some(dir), true) => find_stale_wireguard_peers_files(dir, &manifest, &rendered_names),
+        _ => vec![],
+    };
+    files.retain(|file| only.is_empty() || only.contains(&file.hostname));
 
-    for machine in machines.into_iter() {
+    if !(dry_run || diff) {
+        for file in &files {
+            if read_existing_file(Path::new(&file.path))?.as_ref() != Some(&file.content) {
+                write_file_atomically(Path::new(&file.path), &file.content)?;
+            }
+        }
+        for path in &stale_paths {
+            if remove_stale {
+                fs::remove_file(path).with_context(|| anyhow!("Could not remove {:?}", path))?;
+                eprintln!("Removed stale WireGuard peers file {:?}", path);
+            } else {
+                eprintln!("Stale WireGuard peers file {:?}; remove it with --remove-stale", path);
+            }
+        }
+        if let Some(dir) = &peers_dir {
+            let mut new_manifest = rendered_names;
+            if !only.is_empty() {
+                new_manifest.extend(manifest.iter().cloned());
+            } else if !remove_stale {
+                new_manifest.extend(stale_paths.iter().filter_map(|path| path.file_name()?.to_str().map(String::from)));
+            }
+            if new_manifest != manifest {
+                let content = new_manifest.iter().map(|name| format!("{name}\n")).join("");
+                write_file_atomically(&dir.join(WIREGUARD_PEERS_MANIFEST), &content)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut tw = TabWriter::new(vec![]);
+    write_column_names(&mut tw, vec!["STATUS", "HOSTNAME", "INTERFACE", "PATH"])?;
+    let mut num_changed = 0;
+    for file in &files {
+        let existing = read_existing_file(Path::new(&file.path))?;
+        let status = match &existing {
+            None => "new",
+            Some(existing) if *existing != file.content => "changed",
+            Some(_) => continue,
+        };
+        num_changed += 1;
+        if diff {
+            let old = existing.as_deref().unwrap_or("");
+            let old_header = match existing {
+                Some(_) => file.path.clone(),
+                None => "/dev/null".to_string(),
+            };
+            print!("{}", similar::TextDiff::from_lines(old, &file.content).unified_diff().header(&old_header, &file.path));
+        }
+        writeln!(tw, "{status}\t{}\t{}\t{}", file.hostname, file.interface, file.path)?;
+    }
+    for path in &stale_paths {
+        num_changed += 1;
+        writeln!(tw, "stale\t\t\t{}", path.display())?;
+    }
+    if num_changed == 0 {
+        println!("No WireGuard peers files would change");
+        return Ok(());
+    }
+    if diff {
+        println!();
+    }
+    print_tabwriter(tw)
+}
+
+/// Read a file, or return `None` if it does not exist
+fn read_existing_file(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| anyhow!("Could not read {:?}", path)),
+    }
+}
+
+/// Write a file by renaming a temporary file over it, so that a crash never leaves
+/// a partially-written file behind.  Missing parent directories are created.
+fn write_file_atomically(path: &Path, content: &str) -> Result<()> {
+    write_file_atomically_with(path, content, |_| Ok(()))
+}
+
+/// Like `write_file_atomically`, but call `prepare` on the temporary file before
+/// anything is written to it, e.g. to restrict its permissions.
+fn write_file_atomically_with(path: &Path, content: &str, prepare: impl FnOnce(&File) -> Result<()>) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().with_context(|| anyhow!("{:?} does not have a file name", path))?;
+    fs::create_dir_all(dir).with_context(|| anyhow!("Could not create directory {:?}", dir))?;
+    let temp_path = dir.join(format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id()));
+    let result = (|| -> Result<()> {
+        let mut file = File::create(&temp_path)?;
+        prepare(&file)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.with_context(|| anyhow!("Could not write {:?}", path))
+}
+
+/// Look up the numeric ID of a group in /etc/group
+fn get_group_id(name: &str) -> Result<Option<u32>> {
+    let groups = fs::read_to_string("/etc/group").context("Could not read /etc/group")?;
+    for line in groups.lines() {
+        let fields = line.split(':').collect::<Vec<_>>();
+        if fields.len() >= 3 && fields[0] == name {
+            let gid = fields[2].parse().with_context(|| anyhow!("Invalid group ID for {:?} in /etc/group", name))?;
+            return Ok(Some(gid));
+        }
+    }
+    Ok(None)
+}
+
+/// File next to the WireGuard peers files listing the files `write-wg-peers` wrote,
+/// so that no other file is ever considered stale
+const WIREGUARD_PEERS_MANIFEST: &str = ".infrabase-wg-peers";
+
+/// Get the directory WIREGUARD_PEERS_PATH_TEMPLATE writes to, or `None` if it has placeholders
+/// in its directory and stale files can't be tracked
+fn get_wireguard_peers_dir(path_template: &str) -> Option<PathBuf> {
+    let dir = match Path::new(path_template).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if dir.to_string_lossy().contains('{') {
+        eprintln!("Note: not looking for stale WireGuard peers files because WIREGUARD_PEERS_PATH_TEMPLATE has placeholders in its directory");
+        return None;
+    }
+    Some(dir.to_path_buf())
+}
+
+/// Read the names of the files listed in the WireGuard peers manifest in `dir`
+fn read_wireguard_peers_manifest(dir: &Path) -> Result<BTreeSet<String>> {
+    let content = unwrap_or_else!(read_existing_file(&dir.join(WIREGUARD_PEERS_MANIFEST))?, return Ok(BTreeSet::new()));
+    let names = content.lines()
+        // Only plain file names, so that a bad manifest can't point outside `dir`
+        .filter(|name| !name.is_empty() && !name.contains('/') && *name != "." && *name != "..")
+        .map(String::from)
+        .collect();
+    Ok(names)
+}
+
+/// Find files that an earlier run wrote but that were not rendered this time, such as
+/// files for machines that were removed.  Files not in the manifest are never stale.
+fn find_stale_wireguard_peers_files(dir: &Path, manifest: &BTreeSet<String>, rendered_names: &BTreeSet<String>) -> Vec<PathBuf> {
+    manifest.difference(rendered_names)
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// A WireGuard peers file rendered in memory
+struct WireguardPeersFile {
+    hostname: String,
+    interface: String,
+    path: String,
+    content: String,
+}
+
+fn render_wireguard_peers_for_interface(transaction: &mut Transaction, path_template: &str, interface: &str, with_names: bool) -> Result<Vec<WireguardPeersFile>> {
+    let machines_map = get_machines_with_addresses(transaction, interface)?;
+    let network_links_priority_map = get_network_links_priority_map(transaction)?;
+    let keepalives_map = get_wireguard_keepalive_map(transaction)?;
+    let exit_route_map = get_wireguard_exit_route_map(transaction, interface)?;
+    let hubs_map = get_wireguard_hubs_map(transaction, interface)?;
+    let machines = get_sorted_machines(&machines_map);
+
+    let mut files = vec![];
+    for machine in machines {
+        // Not every machine has every WireGuard interface
+        let wireguard = match machine.wireguard(interface) {
+            Ok(wireguard) => wireguard,
+            Err(_) => continue,
+        };
         let path = path_template
             .replace("{hostname}", &machine.hostname)
-            .replace("{wireguard_ipv4_address}", &machine.wireguard_ipv4_address.unwrap().to_string())
-            .replace("{wireguard_ipv6_address}", &machine.wireguard_ipv6_address.unwrap().to_string());
-        let mut file = File::create(path)?;
-        file.write_all(b"[\n")?;
-        let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, &machine.hostname)?;
+            .replace("{interface}", interface)
+            .replace("{wireguard_ipv4_address}", &wireguard.ipv4_address.to_string())
+            .replace("{wireguard_ipv6_address}", &wireguard.ipv6_address.to_string());
+        let mut peers = get_wireguard_peers(&machines_map, &network_links_priority_map, &keepalives_map, &exit_route_map, &hubs_map, interface, &machine.hostname)?;
         sort_wireguard_peers(&mut peers);
-        for peer in peers {
-            let maybe_endpoint = match peer.endpoint {
-                Some((address, port)) => format!("endpoint = \"{address}:{port}\"; "),
-                None => "".to_string(),
-            };
-            let maybe_keepalive = match peer.keepalive {
-                Some(interval) => format!("persistentKeepalive = {interval}; "),
-                None => "".to_string()
-            };
-            if with_names {
-                writeln!(file, "  {{ name = {}; allowedIPs = [ \"{}/32\" \"{}/128\" ]; publicKey = {}; {maybe_endpoint}{maybe_keepalive}}}",
-                         peer.hostname.to_nix(),
-                         peer.wireguard_ipv4_address,
-                         peer.wireguard_ipv6_address,
-                         peer.wireguard_pubkey.to_nix())?;
-            } else {
-                writeln!(file, "  {{ allowedIPs = [ \"{}/32\" \"{}/128\" ]; publicKey = {}; {maybe_endpoint}{maybe_keepalive}}}",
-                         peer.wireguard_ipv4_address,
-                         peer.wireguard_ipv6_address,
-                         peer.wireguard_pubkey.to_nix())?;
-            }
+        let peers = peers.iter().map(|peer| format_nix_wireguard_peer(peer, with_names)).collect();
+        files.push(WireguardPeersFile {
+            hostname: machine.hostname.clone(),
+            interface: interface.to_string(),
+            path,
+            content: format!("{}\n", NixValue::List(peers)),
+        });
+    }
+    Ok(files)
+}
+
+/// Format a WireGuard peer as an element of a NixOS `networking.wireguard.interfaces.<name>.peers` list
+fn format_nix_wireguard_peer(peer: &WireguardPeer, with_name: bool) -> NixValue {
+    let mut attrs = vec![];
+    if with_name {
+        attrs.push(("name", peer.hostname.to_nix()));
+    }
+    attrs.push(("allowedIPs", get_peer_allowed_ips(peer).to_nix()));
+    attrs.push(("publicKey", peer.wireguard_pubkey.to_nix()));
+    if let Some((address, port)) = peer.endpoint {
+        attrs.push(("endpoint", format!("{address}:{port}").to_nix()));
+    }
+    if let Some(interval) = peer.keepalive {
+        attrs.push(("persistentKeepalive", interval.to_nix()));
+    }
+    NixValue::attrs(attrs)
+}
+
+/// Output a NixOS module that configures all of a machine's WireGuard interfaces
+fn print_nixos_module(transaction: &mut Transaction, for_machine: &str, private_key_file_template: &str, with_names: bool) -> Result<()> {
+    let interfaces = transaction.query("SELECT interface FROM wireguard_interfaces WHERE hostname = $1 ORDER BY interface", &[&for_machine])?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>();
+    ensure!(!interfaces.is_empty(), "Machine {:?} does not have any WireGuard interfaces", for_machine);
+
+    let mut listen_ports = vec![];
+    println!("\
+        # infrabase-generated NixOS module for {for_machine}\n\
+        {{ ... }}:\n\
+        {{\
+    ");
+    for interface in &interfaces {
+        let (my_machine, peers) = get_machine_and_wireguard_peers(transaction, for_machine, interface)?;
+        let settings = &my_machine.wireguard_settings;
+        if !settings.dns.is_empty() {
+            eprintln!("Warning: networking.wireguard.interfaces does not support DNS, not including it in the module for {for_machine} {interface}");
+        }
+        let wireguard = my_machine.wireguard(interface)?;
+        let ips = vec![
+            format!("{}/32", wireguard.ipv4_address),
+            format!("{}/128", wireguard.ipv6_address),
+        ];
+        let listen_port = wireguard.port;
+        listen_ports.push(listen_port);
+        let private_key_file = private_key_file_template
+            .replace("{hostname}", for_machine)
+            .replace("{interface}", interface);
+
+        let mut attrs = vec![
+            ("ips", ips.to_nix()),
+            ("listenPort", listen_port.to_nix()),
+            ("privateKeyFile", private_key_file.to_nix()),
+        ];
+        if let Some(mtu) = settings.mtu {
+            attrs.push(("mtu", mtu.to_nix()));
         }
-        file.write_all(b"]\n")?;
+        match settings.route_table.as_deref() {
+            Some("off") => attrs.push(("allowedIPsAsRoutes", false.to_nix())),
+            None | Some("auto") => {},
+            Some(route_table) => attrs.push(("table", route_table.to_nix())),
+        }
+        if let Some(fwmark) = settings.fwmark {
+            attrs.push(("fwMark", format!("{fwmark:#x}").to_nix()));
+        }
+        for (hook, command) in settings.hooks() {
+            attrs.push((hook.nixos_option(), command.to_nix()));
+        }
+        let peers = peers.iter().map(|peer| format_nix_wireguard_peer(peer, with_names)).collect();
+        attrs.push(("peers", NixValue::List(peers)));
+        println!("  networking.wireguard.interfaces.{} = {};", nix::attr_name(interface), NixValue::attrs(attrs).render(1));
     }
+    println!("  networking.firewall.allowedUDPPorts = {};", listen_ports.to_nix());
+    println!("}}");
     Ok(())
 }
 
@@ -808,62 +4179,913 @@ enum InfrabaseCommand {
     #[structopt(name = "wg-keepalive")]
     WireguardKeepalive(WireguardKeepaliveCommand),
 
-    #[structopt(name = "wg-privkey")]
-    /// Print a machine's private WireGuard key
-    WireguardPrivkey {
+    /// Subcommands to work with WireGuard exit nodes
+    #[structopt(name = "wg-exit")]
+    WireguardExit(WireguardExitCommand),
+
+    /// Subcommands to work with WireGuard interfaces
+    #[structopt(name = "wg-interface")]
+    WireguardInterface(WireguardInterfaceCommand),
+
+    /// Subcommands to enable or disable a machine's WireGuard interface
+    #[structopt(name = "wg")]
+    Wireguard(WireguardCommand),
+
+    /// Subcommands to work with SSH servers
+    #[structopt(name = "ssh-server")]
+    SshServer(SshServerCommand),
+
+    /// Subcommands to enable or disable a machine's SSH server
+    #[structopt(name = "ssh")]
+    Ssh(SshCommand),
+
+    /// Subcommands to work with options added to `ssh-config`
+    #[structopt(name = "ssh-option")]
+    SshOption(SshOptionCommand),
+
+    /// Subcommands to work with the bastions `ssh-config` uses as a ProxyJump
+    #[structopt(name = "ssh-bastion")]
+    SshBastion(SshBastionCommand),
+
+    /// Subcommands to work with SSH host keys
+    #[structopt(name = "ssh-host-key")]
+    SshHostKey(SshHostKeyCommand),
+
+    /// Subcommands to work with the people who log into machines
+    #[structopt(name = "user")]
+    User(UserCommand),
+
+    /// Subcommands to work with users' SSH public keys
+    #[structopt(name = "user-key")]
+    UserKey(UserKeyCommand),
+
+    /// Subcommands to work with the machines users can log into
+    #[structopt(name = "ssh-grant")]
+    SshGrant(SshGrantCommand),
+
+    /// Subcommands to work with WireGuard overlay address pools
+    #[structopt(name = "wg-overlay")]
+    WireguardOverlay(WireguardOverlayCommand),
+
+    /// Subcommands to work with the WireGuard hub-and-spoke topology
+    #[structopt(name = "wg-topology")]
+    WireguardTopology(WireguardTopologyCommand),
+
+    #[structopt(name = "wg-privkey")]
+    /// Print a machine's private WireGuard key
+    WireguardPrivkey {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "write-wg-peers")]
+    /// Write out all WireGuard peers files used for NixOS configuration
+    WriteWireguardPeers {
+        /// Omit the `name = "..."` not supported in upstream nixpkgs
+        #[structopt(long = "no-names")]
+        no_names: bool,
+
+        /// List the files that would be created or changed instead of writing them
+        #[structopt(long)]
+        dry_run: bool,
+
+        /// Like --dry-run, but also show a unified diff of each file
+        #[structopt(long)]
+        diff: bool,
+
+        /// Remove files that an earlier run wrote but that are no longer generated.  Written files
+        /// are listed in .infrabase-wg-peers next to them, and no other file is ever removed.
+        /// Stale files are not looked for when using --only.
+        #[structopt(long)]
+        remove_stale: bool,
+
+        /// Only write the files for these machines
+        #[structopt(long, value_name = "HOSTNAME")]
+        only: Vec<String>,
+    },
+
+    /// Subcommands to work with providers
+    #[structopt(name = "provider")]
+    Provider(ProviderCommand),
+
+    /// Subcommands to work with addresses
+    #[structopt(name = "address")]
+    Address(AddressCommand),
+
+    #[structopt(name = "ls")]
+    /// List machines
+    List,
+
+    #[structopt(name = "nmconnection")]
+    /// Output a NetworkManager keyfile for a machine's WireGuard interface
+    Nmconnection {
+        /// Machine to generate NetworkManager keyfile for
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "nixos-module")]
+    /// Output a NixOS module that configures all of a machine's WireGuard interfaces
+    NixosModule {
+        /// Machine to generate NixOS module for
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: String,
+
+        /// Path of the private key file on the machine; {hostname} and {interface} are replaced
+        #[structopt(long, default_value = "/etc/wireguard/{interface}.key")]
+        private_key_file: String,
+
+        /// Omit the `name = "..."` not supported in upstream nixpkgs
+        #[structopt(long = "no-names")]
+        no_names: bool,
+    },
+
+    #[structopt(name = "import")]
+    /// Add everything missing from a JSON export or a CSV of machines and addresses
+    Import {
+        /// JSON file in the format written by `export`, or a .csv file with the columns
+        /// hostname, owner, provider_id, provider_reference, ssh_port, ssh_user, wireguard_interface,
+        /// wireguard_port, network, address, address_ssh_port, address_wireguard_port.
+        /// Machines without SSH or WireGuard columns get no SSH server or WireGuard interface.
+        /// address_wireguard_port only applies to the default interface (wg0), like `address add --wireguard-port`.
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Make the changes instead of only printing them
+        #[structopt(long)]
+        apply: bool,
+    },
+
+    #[structopt(name = "apply")]
+    /// Make the database match a desired-state file, creating, updating, and deleting records
+    Apply {
+        /// JSON or .toml file in the format written by `export`; only the top-level sections
+        /// in the file, and the fields present for each machine, are synced
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Only print the changes
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    #[structopt(name = "export")]
+    /// Output the whole inventory, without private keys, as JSON
+    Export,
+
+    #[structopt(name = "nix-data")]
+    /// Output machine and address data in Nix format for use in configuration
+    NixData,
+
+    #[structopt(name = "add")]
+    /// Add machine
+    Add {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Machine owner
+        ///
+        /// If one is not provided, DEFAULT_OWNER will be used from the environment.
+        #[structopt(long)]
+        owner: Option<String>,
+
+        /// Don't add an SSH server, for machines that can't be logged into
+        ///
+        /// One can be added later with `ssh enable`.
+        #[structopt(long, conflicts_with_all = &["ssh-port", "ssh-user"])]
+        no_ssh: bool,
+
+        /// SSH port
+        ///
+        /// If one is not provided, DEFAULT_SSH_PORT will be used from the environment.
+        #[structopt(long)]
+        ssh_port: Option<u16>,
+
+        /// SSH user
+        ///
+        /// If one is not provided, DEFAULT_SSH_USER will be used from the environment.
+        #[structopt(long)]
+        ssh_user: Option<String>,
+
+        /// Don't add a WireGuard interface, for machines that are not part of the overlay
+        ///
+        /// One can be added later with `wg enable`.
+        #[structopt(long, conflicts_with_all = &["wireguard-ipv4-address", "wireguard-ipv6-address", "wireguard-port"])]
+        no_wireguard: bool,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        wireguard_interface: String,
+
+        /// WireGuard IPv4 IP
+        ///
+        /// If one is not provided, an unused IP address will be selected.
+        #[structopt(long)]
+        wireguard_ipv4_address: Option<Ipv4Addr>,
+
+        /// WireGuard IPv6 IP
+        ///
+        /// If one is not provided, an unused IP address will be selected.
+        #[structopt(long)]
+        wireguard_ipv6_address: Option<Ipv6Addr>,
+
+        /// WireGuard port
+        ///
+        /// If one is not provided, DEFAULT_WIREGUARD_PORT will be used from the environment.
+        #[structopt(long)]
+        wireguard_port: Option<u16>,
+
+        /// Provider
+        ///
+        /// If one is not provided, DEFAULT_OWNER will be used from the environment
+        /// if set, otherwise it will be left unset.
+        #[structopt(long)]
+        provider: Option<i32>,
+
+        /// Provider reference
+        ///
+        /// An optional arbitrary string used to correlate this machine with some reference
+        /// at the provider, like a contract ID or a server number.
+        #[structopt(long)]
+        provider_reference: Option<String>,
+    },
+
+    #[structopt(name = "prometheus-sd")]
+    /// Output Prometheus file_sd_configs JSON with a target for every machine
+    PrometheusSd {
+        /// Machine that Prometheus runs on, required with --best-address unless --from-network is given
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: Option<String>,
+
+        /// With --best-address, use addresses reachable from these networks instead of from a machine
+        #[structopt(long, value_name = "NETWORK", conflicts_with = "MACHINE", requires = "best-address")]
+        from_network: Vec<String>,
+
+        /// WireGuard interface whose IPv4 address is used for each target
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+
+        /// Port to scrape on each machine
+        #[structopt(long, default_value = "9100")]
+        port: u16,
+
+        /// Use the address `ssh-config` would use instead of the WireGuard IP, for monitoring without WireGuard
+        #[structopt(long)]
+        best_address: bool,
+
+        /// Write the JSON to this file, atomically, instead of printing it
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove machine
+    Remove {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+
+    #[structopt(name = "ssh-config")]
+    /// Prints an ~/.ssh/config that lists all machines
+    SshConfig {
+        /// Machine to generate SSH config for
+        #[structopt(long = "for", name = "MACHINE", required_unless = "from-network")]
+        r#for: Option<String>,
+
+        /// Generate SSH config for a client that is not in the inventory, like a laptop, on these networks.
+        /// Use NONE for a client that can only reach the networks that NONE links to.
+        #[structopt(long, value_name = "NETWORK", conflicts_with = "MACHINE")]
+        from_network: Vec<String>,
+
+        /// Also add hostname.wg, hostname.v4, and hostname.v6 hosts to connect over
+        /// WireGuard, IPv4, or IPv6
+        #[structopt(long)]
+        aliases: bool,
+    },
+
+    #[structopt(name = "ansible-inventory")]
+    /// Output an Ansible inventory that connects to machines like `ssh-config` does
+    ///
+    /// Machines are grouped by owner, provider, and network.  For use as a dynamic inventory,
+    /// wrap `ansible-inventory --for MACHINE "$@"` in a script and pass it to ansible -i.
+    AnsibleInventory {
+        /// Control machine that Ansible runs on
+        #[structopt(long = "for", name = "MACHINE", required_unless = "from-network")]
+        r#for: Option<String>,
+
+        /// Generate an inventory for a control machine that is not in the inventory, on these networks
+        #[structopt(long, value_name = "NETWORK", conflicts_with = "MACHINE")]
+        from_network: Vec<String>,
+
+        /// Output JSON instead of YAML
+        #[structopt(long)]
+        json: bool,
+
+        /// Output all groups and host variables in the JSON format of a dynamic inventory script
+        #[structopt(long, conflicts_with_all = &["json", "host"])]
+        list: bool,
+
+        /// Output the variables of one host in the JSON format of a dynamic inventory script
+        #[structopt(long, value_name = "HOSTNAME", conflicts_with = "json")]
+        host: Option<String>,
+    },
+
+    #[structopt(name = "authorized-keys")]
+    /// Output the authorized_keys file for each login on a machine
+    AuthorizedKeys {
+        /// Machine to generate authorized_keys for
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: String,
+
+        /// Output a NixOS module setting users.users.<login>.openssh.authorizedKeys.keys instead
+        #[structopt(long)]
+        nix: bool,
+
+        /// Write a file named after each login to this directory instead of printing them.
+        /// Logins that had keys before but no longer do get a file without keys.
+        #[structopt(long, parse(from_os_str), conflicts_with = "nix")]
+        output_dir: Option<PathBuf>,
+    },
+
+    #[structopt(name = "hosts")]
+    /// Output an /etc/hosts fragment, or unbound or dnsmasq records, for reaching every other machine
+    ///
+    /// Each machine resolves to the address `ssh-config` would use to reach it.
+    Hosts {
+        /// Machine to generate records for
+        #[structopt(long = "for", name = "MACHINE", required_unless = "from-network")]
+        r#for: Option<String>,
+
+        /// Generate records for a client that is not in the inventory, on these networks
+        #[structopt(long, value_name = "NETWORK", conflicts_with = "MACHINE")]
+        from_network: Vec<String>,
+
+        /// Domain to add fully-qualified names in, like the zone given to `dns-zone`
+        #[structopt(long)]
+        domain: Option<String>,
+
+        /// Output format: hosts, unbound, or dnsmasq
+        #[structopt(long, default_value = "hosts")]
+        format: HostsFormat,
+    },
+
+    #[structopt(name = "known-hosts")]
+    /// Output a known_hosts file for every way `ssh-config` connects to each machine
+    KnownHosts {
+        /// Machine to generate known_hosts for
+        #[structopt(long = "for", name = "MACHINE", required_unless = "from-network")]
+        r#for: Option<String>,
+
+        /// Generate known_hosts for a client that is not in the inventory, on these networks
+        #[structopt(long, value_name = "NETWORK", conflicts_with = "MACHINE")]
+        from_network: Vec<String>,
+    },
+
+    #[structopt(name = "dns-zone")]
+    /// Output a BIND zone file with records for every machine's WireGuard and network addresses
+    DnsZone {
+        /// Zone name, like example.internal
+        #[structopt(long)]
+        zone: String,
+
+        /// WireGuard interface whose addresses are used for the records at each hostname
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+
+        #[structopt(flatten)]
+        options: ZoneOptions,
+    },
+
+    #[structopt(name = "dns-reverse-zones")]
+    /// Output BIND reverse zone files with PTR records for the addresses in a WireGuard pool
+    DnsReverseZones {
+        /// Forward zone created with `dns-zone`, which the PTR records point into
+        #[structopt(long)]
+        zone: String,
+
+        /// WireGuard interface whose address pool the reverse zones cover
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+
+        /// Give parts of the IPv4 pool smaller than a /24 their own RFC 2317 classless zones instead of a whole /24 zone
+        #[structopt(long)]
+        rfc2317: bool,
+
+        /// Write a file for each zone to this directory instead of printing them
+        #[structopt(long, parse(from_os_str))]
+        output_dir: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        options: ZoneOptions,
+    },
+
+    #[structopt(name = "networkd")]
+    /// Output systemd-networkd .netdev and .network files for a machine's WireGuard interface
+    Networkd {
+        /// Machine to generate systemd-networkd config for
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+
+        /// Write the files to this directory (e.g. /etc/systemd/network) instead of printing them.
+        /// The .netdev file is written with mode 0640 and group systemd-network.
+        #[structopt(long, parse(from_os_str))]
+        output_dir: Option<PathBuf>,
+    },
+
+    #[structopt(name = "wg-quick")]
+    /// Output a wg-quick config for a machine
+    WgQuick {
+        /// Machine to generate wg-quick config for
+        #[structopt(long = "for", name = "MACHINE")]
+        r#for: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum WireguardKeepaliveCommand {
+    #[structopt(name = "ls")]
+    /// List persistent keepalives
+    List,
+
+    #[structopt(name = "add")]
+    /// Add persistent keepalive
+    Add {
+        /// Source machine hostname
+        #[structopt(name = "SOURCE")]
+        source: String,
+
+        /// Target machine hostname
+        #[structopt(name = "TARGET")]
+        target: String,
+
+        /// SSH port
+        ///
+        /// If one is not provided, DEFAULT_WIREGUARD_KEEPALIVE_INTERVAL_SEC will be used from the environment.
+        #[structopt(long)]
+        interval_sec: Option<u16>,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove persistent keepalive
+    Remove {
+        /// Source machine hostname
+        #[structopt(name = "SOURCE")]
+        source: String,
+
+        /// Target machine hostname
+        #[structopt(name = "TARGET")]
+        target: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum WireguardExitCommand {
+    #[structopt(name = "ls")]
+    /// List machines routing all of their traffic through an exit node
+    List,
+
+    #[structopt(name = "enable")]
+    /// Allow other machines to route all of their traffic through a machine
+    Enable {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "disable")]
+    /// Stop allowing other machines to route all of their traffic through a machine
+    Disable {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "add")]
+    /// Route all of a machine's traffic through an exit node
+    Add {
+        /// Source machine hostname
+        #[structopt(name = "SOURCE")]
+        source: String,
+
+        /// Exit node hostname
+        #[structopt(name = "EXIT")]
+        exit: String,
+
+        /// WireGuard interface to route the traffic over
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "rm")]
+    /// Stop routing a machine's traffic through its exit node
+    Remove {
+        /// Source machine hostname
+        #[structopt(name = "SOURCE")]
+        source: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SshCommand {
+    #[structopt(name = "enable")]
+    /// Add an SSH server to a machine, like `ssh-server add`
+    Enable {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// SSH port
+        ///
+        /// If one is not provided, DEFAULT_SSH_PORT will be used from the environment.
+        #[structopt(long)]
+        ssh_port: Option<u16>,
+
+        /// SSH user
+        ///
+        /// If one is not provided, DEFAULT_SSH_USER will be used from the environment.
+        #[structopt(long)]
+        ssh_user: Option<String>,
+    },
+
+    #[structopt(name = "disable")]
+    /// Remove a machine's SSH server, like `ssh-server rm`
+    Disable {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SshServerCommand {
+    #[structopt(name = "ls")]
+    /// List SSH servers
+    List,
+
+    #[structopt(name = "add")]
+    /// Add an SSH server to a machine
+    Add {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// SSH port
+        ///
+        /// If one is not provided, DEFAULT_SSH_PORT will be used from the environment.
+        #[structopt(long)]
+        ssh_port: Option<u16>,
+
+        /// SSH user
+        ///
+        /// If one is not provided, DEFAULT_SSH_USER will be used from the environment.
+        #[structopt(long)]
+        ssh_user: Option<String>,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove a machine's SSH server, leaving it out of `ssh-config`
+    Remove {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SshHostKeyCommand {
+    #[structopt(name = "ls")]
+    /// List SSH host keys
+    List,
+
+    #[structopt(name = "add")]
+    /// Add or replace a machine's SSH host key of a type
+    Add {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Key type, like ssh-ed25519
+        #[structopt(name = "TYPE")]
+        key_type: String,
+
+        /// Base64 public key
+        #[structopt(name = "PUBLIC_KEY")]
+        public_key: String,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove a machine's SSH host key of a type
+    Remove {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Key type, like ssh-ed25519
+        #[structopt(name = "TYPE")]
+        key_type: String,
+    },
+
+    #[structopt(name = "import")]
+    /// Add or replace SSH host keys from the output of ssh-keyscan
+    Import {
+        /// File with ssh-keyscan output
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Machine the keys belong to
+        ///
+        /// If one is not provided, each key's machine is found by the host name or address ssh-keyscan printed.
+        #[structopt(long)]
+        hostname: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum UserCommand {
+    #[structopt(name = "ls")]
+    /// List users
+    List,
+
+    #[structopt(name = "add")]
+    /// Add a user
+    Add {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove a user along with their SSH keys and grants
+    Remove {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum UserKeyCommand {
+    #[structopt(name = "ls")]
+    /// List users' SSH public keys
+    List,
+
+    #[structopt(name = "add")]
+    /// Add an SSH public key to a user
+    Add {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+
+        /// Public key, like "ssh-ed25519 AAAA... comment"
+        #[structopt(name = "KEY")]
+        key: String,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove an SSH public key from a user
+    Remove {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+
+        /// Public key, or just its base64 part
+        #[structopt(name = "KEY")]
+        key: String,
+    },
+}
+
+/// Which machines a user can log into
+#[derive(StructOpt, Debug)]
+#[structopt(group = structopt::clap::ArgGroup::with_name("target").required(true))]
+struct SshGrantTarget {
+    /// Machine hostname
+    #[structopt(long, group = "target")]
+    machine: Option<String>,
+
+    /// Owner; grants access to all of the owner's machines
+    #[structopt(long, group = "target")]
+    owner: Option<String>,
+
+    /// Account to log into
+    ///
+    /// If one is not provided, each machine's SSH user is used.
+    #[structopt(long)]
+    login: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+enum SshGrantCommand {
+    #[structopt(name = "ls")]
+    /// List the machines users can log into
+    List,
+
+    #[structopt(name = "add")]
+    /// Let a user log into a machine or all of an owner's machines
+    Add {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+
+        #[structopt(flatten)]
+        target: SshGrantTarget,
+    },
+
+    #[structopt(name = "rm")]
+    /// Remove a grant added with `ssh-grant add`
+    Remove {
+        /// User name
+        #[structopt(name = "NAME")]
+        name: String,
+
+        #[structopt(flatten)]
+        target: SshGrantTarget,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SshBastionCommand {
+    #[structopt(name = "ls")]
+    /// List bastions set for machines
+    List,
+
+    #[structopt(name = "add")]
+    /// Use a bastion as the ProxyJump for a machine whenever it can't be reached directly
+    ///
+    /// Without one, `ssh-config` picks a bastion that can reach the machine through the network links.
+    Add {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Bastion hostname
+        #[structopt(name = "BASTION")]
+        bastion: String,
+    },
+
+    #[structopt(name = "rm")]
+    /// Go back to picking a bastion for a machine through the network links
+    Remove {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+    },
+}
+
+/// Which machines an ssh_config option applies to
+#[derive(StructOpt, Debug)]
+#[structopt(group = structopt::clap::ArgGroup::with_name("scope").required(true))]
+struct SshOptionTarget {
+    /// Machine hostname
+    #[structopt(long, group = "scope")]
+    machine: Option<String>,
+
+    /// Network; applies when connecting to an address on this network
+    #[structopt(long, group = "scope")]
+    network: Option<String>,
+
+    /// Owner; applies to all of the owner's machines
+    #[structopt(long, group = "scope")]
+    owner: Option<String>,
+}
+
+impl SshOptionTarget {
+    fn scope(&self) -> (SshOptionScope, &str) {
+        match (&self.machine, &self.network, &self.owner) {
+            (Some(machine), _, _) => (SshOptionScope::Machine, machine),
+            (_, Some(network), _) => (SshOptionScope::Network, network),
+            (_, _, Some(owner)) => (SshOptionScope::Owner, owner),
+            _ => unreachable!("clap requires one of --machine, --network, or --owner"),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum SshOptionCommand {
+    #[structopt(name = "ls")]
+    /// List ssh_config options
+    List,
+
+    #[structopt(name = "set")]
+    /// Set an ssh_config option, like IdentityFile or ServerAliveInterval, for a machine, network, or owner
+    ///
+    /// Machine options take precedence over network options, which take precedence over owner options.
+    Set {
+        #[structopt(flatten)]
+        target: SshOptionTarget,
+
+        /// ssh_config keyword
+        #[structopt(name = "OPTION")]
+        option: String,
+
+        /// Value
+        #[structopt(name = "VALUE")]
+        value: String,
+    },
+
+    #[structopt(name = "unset")]
+    /// Unset an ssh_config option for a machine, network, or owner
+    Unset {
+        #[structopt(flatten)]
+        target: SshOptionTarget,
+
+        /// ssh_config keyword
+        #[structopt(name = "OPTION")]
+        option: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum WireguardCommand {
+    #[structopt(name = "enable")]
+    /// Add a WireGuard interface with a new keypair to a machine, like `wg-interface add`
+    Enable {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Interface name
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+
+        /// WireGuard IPv4 IP
+        ///
+        /// If one is not provided, an unused IP address will be selected.
+        #[structopt(long)]
+        wireguard_ipv4_address: Option<Ipv4Addr>,
+
+        /// WireGuard IPv6 IP
+        ///
+        /// If one is not provided, an unused IP address will be selected.
+        #[structopt(long)]
+        wireguard_ipv6_address: Option<Ipv6Addr>,
+
+        /// WireGuard port
+        ///
+        /// If one is not provided, DEFAULT_WIREGUARD_PORT will be used from the environment.
+        /// Peers connect to interfaces other than the default (wg0) on this port, not on
+        /// the WireGuard port of the machine's address.
+        #[structopt(long)]
+        wireguard_port: Option<u16>,
+    },
+
+    #[structopt(name = "disable")]
+    /// Remove a machine's WireGuard interface, like `wg-interface rm`
+    Disable {
         /// Machine hostname
         #[structopt(name = "HOSTNAME")]
         hostname: String,
-    },
 
-    #[structopt(name = "write-wg-peers")]
-    /// Write out all WireGuard peers files used for NixOS configuration
-    WriteWireguardPeers {
-        /// Omit the `name = "..."` not supported in upstream nixpkgs
-        #[structopt(long = "no-names")]
-        no_names: bool,
+        /// Interface name
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
     },
+}
 
-    /// Subcommands to work with providers
-    #[structopt(name = "provider")]
-    Provider(ProviderCommand),
-
-    /// Subcommands to work with addresses
-    #[structopt(name = "address")]
-    Address(AddressCommand),
-
+#[derive(StructOpt, Debug)]
+enum WireguardInterfaceCommand {
     #[structopt(name = "ls")]
-    /// List machines
+    /// List WireGuard interfaces
     List,
 
-    #[structopt(name = "nix-data")]
-    /// Output machine and address data in Nix format for use in configuration
-    NixData,
-
     #[structopt(name = "add")]
-    /// Add machine
+    /// Add a WireGuard interface with a new keypair to a machine
     Add {
         /// Machine hostname
         #[structopt(name = "HOSTNAME")]
         hostname: String,
 
-        /// Machine owner
-        ///
-        /// If one is not provided, DEFAULT_OWNER will be used from the environment.
-        #[structopt(long)]
-        owner: Option<String>,
-
-        /// SSH port
-        ///
-        /// If one is not provided, DEFAULT_SSH_PORT will be used from the environment.
-        #[structopt(long)]
-        ssh_port: Option<u16>,
-
-        /// SSH user
+        /// Interface name
         ///
-        /// If one is not provided, DEFAULT_SSH_USER will be used from the environment.
-        #[structopt(long)]
-        ssh_user: Option<String>,
+        /// Machines with an interface of the same name are peers of each other.
+        #[structopt(name = "INTERFACE")]
+        interface: String,
 
         /// WireGuard IPv4 IP
         ///
@@ -880,83 +5102,172 @@ enum InfrabaseCommand {
         /// WireGuard port
         ///
         /// If one is not provided, DEFAULT_WIREGUARD_PORT will be used from the environment.
+        /// Peers connect to interfaces other than the default (wg0) on this port, not on
+        /// the WireGuard port of the machine's address.
         #[structopt(long)]
         wireguard_port: Option<u16>,
-
-        /// Provider
-        ///
-        /// If one is not provided, DEFAULT_OWNER will be used from the environment
-        /// if set, otherwise it will be left unset.
-        #[structopt(long)]
-        provider: Option<i32>,
-
-        /// Provider reference
-        ///
-        /// An optional arbitrary string used to correlate this machine with some reference
-        /// at the provider, like a contract ID or a server number.
-        #[structopt(long)]
-        provider_reference: Option<String>,
     },
 
     #[structopt(name = "rm")]
-    /// Remove machine
+    /// Remove a WireGuard interface from a machine
     Remove {
         /// Machine hostname
         #[structopt(name = "HOSTNAME")]
         hostname: String,
+
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
     },
 
-    #[structopt(name = "ssh-config")]
-    /// Prints an ~/.ssh/config that lists all machines
-    SshConfig {
-        /// Machine to generate SSH config for
-        #[structopt(long = "for", name = "MACHINE")]
-        r#for: String,
+    #[structopt(name = "set")]
+    /// Set a wg-quick [Interface] setting on a machine's WireGuard interface
+    ///
+    /// This overrides the default set with `wg-overlay set`.
+    Set {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
+
+        /// Setting: mtu, dns, table, fwmark, pre-up, post-up, pre-down, or post-down
+        #[structopt(name = "SETTING")]
+        setting: WireguardSetting,
+
+        /// Value; for dns, a comma-separated list of servers and search domains
+        #[structopt(name = "VALUE")]
+        value: String,
     },
 
-    #[structopt(name = "wg-quick")]
-    /// Output a wg-quick config for a machine
-    WgQuick {
-        /// Machine to generate wg-quick config for
-        #[structopt(long = "for", name = "MACHINE")]
-        r#for: String,
+    #[structopt(name = "unset")]
+    /// Unset a setting on a machine's WireGuard interface, falling back to the default
+    Unset {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
+
+        /// Setting: mtu, dns, table, fwmark, pre-up, post-up, pre-down, or post-down
+        #[structopt(name = "SETTING")]
+        setting: WireguardSetting,
     },
 }
 
 #[derive(StructOpt, Debug)]
-enum WireguardKeepaliveCommand {
+enum WireguardOverlayCommand {
     #[structopt(name = "ls")]
-    /// List persistent keepalives
+    /// List WireGuard overlay address pools and default MTUs
     List,
 
     #[structopt(name = "add")]
-    /// Add persistent keepalive
+    /// Set the address pool for WireGuard interfaces with some name
+    ///
+    /// Interfaces without an address pool use WIREGUARD_IPV4_START, WIREGUARD_IPV4_END,
+    /// WIREGUARD_IPV6_START, and WIREGUARD_IPV6_END from the environment.
     Add {
-        /// Source machine hostname
-        #[structopt(name = "SOURCE")]
-        source: String,
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
 
-        /// Target machine hostname
-        #[structopt(name = "TARGET")]
-        target: String,
+        /// First IPv4 address in the pool
+        #[structopt(name = "IPV4_START")]
+        ipv4_start: Ipv4Addr,
 
-        /// SSH port
-        ///
-        /// If one is not provided, DEFAULT_WIREGUARD_KEEPALIVE_INTERVAL_SEC will be used from the environment.
-        #[structopt(long)]
-        interval_sec: Option<u16>,
+        /// Last IPv4 address in the pool
+        #[structopt(name = "IPV4_END")]
+        ipv4_end: Ipv4Addr,
+
+        /// First IPv6 address in the pool
+        #[structopt(name = "IPV6_START")]
+        ipv6_start: Ipv6Addr,
+
+        /// Last IPv6 address in the pool
+        #[structopt(name = "IPV6_END")]
+        ipv6_end: Ipv6Addr,
     },
 
     #[structopt(name = "rm")]
-    /// Remove persistent keepalive
+    /// Remove a WireGuard overlay address pool and default settings
     Remove {
-        /// Source machine hostname
-        #[structopt(name = "SOURCE")]
-        source: String,
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
+    },
 
-        /// Target machine hostname
-        #[structopt(name = "TARGET")]
-        target: String,
+    #[structopt(name = "set")]
+    /// Set the default for a wg-quick [Interface] setting on all WireGuard interfaces with some name
+    Set {
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
+
+        /// Setting: mtu, dns, table, fwmark, pre-up, post-up, pre-down, or post-down
+        #[structopt(name = "SETTING")]
+        setting: WireguardSetting,
+
+        /// Value; for dns, a comma-separated list of servers and search domains
+        #[structopt(name = "VALUE")]
+        value: String,
+    },
+
+    #[structopt(name = "unset")]
+    /// Unset the default for a setting on all WireGuard interfaces with some name
+    Unset {
+        /// Interface name
+        #[structopt(name = "INTERFACE")]
+        interface: String,
+
+        /// Setting: mtu, dns, table, fwmark, pre-up, post-up, pre-down, or post-down
+        #[structopt(name = "SETTING")]
+        setting: WireguardSetting,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum WireguardTopologyCommand {
+    #[structopt(name = "ls")]
+    /// List the WireGuard role and hubs of each machine
+    List {
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "mesh")]
+    /// Make a machine peer with every other mesh machine
+    Mesh {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
+    },
+
+    #[structopt(name = "spoke")]
+    /// Make a machine peer only with its hubs
+    Spoke {
+        /// Machine hostname
+        #[structopt(name = "HOSTNAME")]
+        hostname: String,
+
+        /// Mesh machines to relay traffic for this machine
+        ///
+        /// Mesh machines that are not one of these hubs will reach this machine
+        /// through the first hub by hostname.
+        #[structopt(name = "HUB", required = true)]
+        hubs: Vec<String>,
+
+        /// WireGuard interface
+        #[structopt(long, default_value = DEFAULT_WIREGUARD_INTERFACE)]
+        interface: String,
     },
 }
 
@@ -994,9 +5305,11 @@ enum AddressCommand {
         #[structopt(long)]
         ssh_port: Option<u16>,
 
-        /// WireGuard port
+        /// WireGuard port that peers connect to on this address, which may be forwarded through a NAT
         ///
         /// If one is not provided, DEFAULT_WIREGUARD_PORT will be used from the environment.
+        /// This port is only used for the default interface (wg0).  Peers connect to other
+        /// interfaces on the interface's own port, so forward that port unchanged.
         #[structopt(long)]
         wireguard_port: Option<u16>,
     },
@@ -1054,29 +5367,231 @@ fn main() -> Result<()> {
                 },
             }
         },
-        InfrabaseCommand::WireguardPrivkey { hostname } => {
-            print_wireguard_privkey(&mut transaction, &hostname)?;
+        InfrabaseCommand::WireguardExit(cmd) => {
+            match cmd {
+                WireguardExitCommand::List => list_wireguard_exit_routes(&mut transaction)?,
+                WireguardExitCommand::Enable { hostname, interface } => {
+                    set_wireguard_exit_node(transaction, &hostname, &interface, true)?
+                },
+                WireguardExitCommand::Disable { hostname, interface } => {
+                    set_wireguard_exit_node(transaction, &hostname, &interface, false)?
+                },
+                WireguardExitCommand::Add { source, exit, interface } => {
+                    add_wireguard_exit_route(transaction, &source, &exit, &interface)?
+                },
+                WireguardExitCommand::Remove { source } => {
+                    remove_wireguard_exit_route(transaction, &source)?
+                },
+            }
+        },
+        InfrabaseCommand::WireguardInterface(cmd) => {
+            match cmd {
+                WireguardInterfaceCommand::List => list_wireguard_interfaces(&mut transaction)?,
+                WireguardInterfaceCommand::Add { hostname, interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port } => {
+                    add_wireguard_interface(transaction, &hostname, &interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port)?
+                },
+                WireguardInterfaceCommand::Remove { hostname, interface } => {
+                    remove_wireguard_interface(transaction, &hostname, &interface)?
+                },
+                WireguardInterfaceCommand::Set { hostname, interface, setting, value } => {
+                    set_wireguard_interface_setting(transaction, &hostname, &interface, setting, Some(&value))?
+                },
+                WireguardInterfaceCommand::Unset { hostname, interface, setting } => {
+                    set_wireguard_interface_setting(transaction, &hostname, &interface, setting, None)?
+                },
+            }
+        },
+        InfrabaseCommand::Wireguard(cmd) => {
+            match cmd {
+                WireguardCommand::Enable { hostname, interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port } => {
+                    add_wireguard_interface(transaction, &hostname, &interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port)?
+                },
+                WireguardCommand::Disable { hostname, interface } => {
+                    remove_wireguard_interface(transaction, &hostname, &interface)?
+                },
+            }
+        },
+        InfrabaseCommand::SshServer(cmd) => {
+            match cmd {
+                SshServerCommand::List => list_ssh_servers(&mut transaction)?,
+                SshServerCommand::Add { hostname, ssh_port, ssh_user } => {
+                    add_ssh_server(transaction, &hostname, ssh_port, ssh_user)?
+                },
+                SshServerCommand::Remove { hostname } => {
+                    remove_ssh_server(transaction, &hostname)?
+                },
+            }
+        },
+        InfrabaseCommand::Ssh(cmd) => {
+            match cmd {
+                SshCommand::Enable { hostname, ssh_port, ssh_user } => {
+                    add_ssh_server(transaction, &hostname, ssh_port, ssh_user)?
+                },
+                SshCommand::Disable { hostname } => {
+                    remove_ssh_server(transaction, &hostname)?
+                },
+            }
+        },
+        InfrabaseCommand::SshOption(cmd) => {
+            match cmd {
+                SshOptionCommand::List => list_ssh_options(&mut transaction)?,
+                SshOptionCommand::Set { target, option, value } => {
+                    let (scope, name) = target.scope();
+                    set_ssh_option(transaction, scope, name, &option, Some(&value))?
+                },
+                SshOptionCommand::Unset { target, option } => {
+                    let (scope, name) = target.scope();
+                    set_ssh_option(transaction, scope, name, &option, None)?
+                },
+            }
+        },
+        InfrabaseCommand::SshBastion(cmd) => {
+            match cmd {
+                SshBastionCommand::List => list_ssh_bastions(&mut transaction)?,
+                SshBastionCommand::Add { hostname, bastion } => {
+                    add_ssh_bastion(transaction, &hostname, &bastion)?
+                },
+                SshBastionCommand::Remove { hostname } => {
+                    remove_ssh_bastion(transaction, &hostname)?
+                },
+            }
+        },
+        InfrabaseCommand::SshHostKey(cmd) => {
+            match cmd {
+                SshHostKeyCommand::List => list_ssh_host_keys(&mut transaction)?,
+                SshHostKeyCommand::Add { hostname, key_type, public_key } => {
+                    add_ssh_host_key(transaction, &hostname, &key_type, &public_key)?
+                },
+                SshHostKeyCommand::Remove { hostname, key_type } => {
+                    remove_ssh_host_key(transaction, &hostname, &key_type)?
+                },
+                SshHostKeyCommand::Import { file, hostname } => {
+                    import_ssh_host_keys(transaction, &file, hostname.as_deref())?
+                },
+            }
+        },
+        InfrabaseCommand::User(cmd) => {
+            match cmd {
+                UserCommand::List => list_users(&mut transaction)?,
+                UserCommand::Add { name } => add_user(transaction, &name)?,
+                UserCommand::Remove { name } => remove_user(transaction, &name)?,
+            }
+        },
+        InfrabaseCommand::UserKey(cmd) => {
+            match cmd {
+                UserKeyCommand::List => list_user_ssh_keys(&mut transaction)?,
+                UserKeyCommand::Add { name, key } => add_user_ssh_key(transaction, &name, &key)?,
+                UserKeyCommand::Remove { name, key } => remove_user_ssh_key(transaction, &name, &key)?,
+            }
+        },
+        InfrabaseCommand::SshGrant(cmd) => {
+            match cmd {
+                SshGrantCommand::List => list_ssh_grants(&mut transaction)?,
+                SshGrantCommand::Add { name, target } => {
+                    add_ssh_grant(transaction, &name, target.machine.as_deref(), target.owner.as_deref(), target.login.as_deref())?
+                },
+                SshGrantCommand::Remove { name, target } => {
+                    remove_ssh_grant(transaction, &name, target.machine.as_deref(), target.owner.as_deref(), target.login.as_deref())?
+                },
+            }
+        },
+        InfrabaseCommand::WireguardOverlay(cmd) => {
+            match cmd {
+                WireguardOverlayCommand::List => list_wireguard_overlays(&mut transaction)?,
+                WireguardOverlayCommand::Add { interface, ipv4_start, ipv4_end, ipv6_start, ipv6_end } => {
+                    add_wireguard_overlay(transaction, &interface, &WireguardPool { ipv4_start, ipv4_end, ipv6_start, ipv6_end })?
+                },
+                WireguardOverlayCommand::Remove { interface } => {
+                    remove_wireguard_overlay(transaction, &interface)?
+                },
+                WireguardOverlayCommand::Set { interface, setting, value } => {
+                    set_wireguard_overlay_setting(transaction, &interface, setting, Some(&value))?
+                },
+                WireguardOverlayCommand::Unset { interface, setting } => {
+                    set_wireguard_overlay_setting(transaction, &interface, setting, None)?
+                },
+            }
+        },
+        InfrabaseCommand::WireguardTopology(cmd) => {
+            match cmd {
+                WireguardTopologyCommand::List { interface } => list_wireguard_topology(&mut transaction, &interface)?,
+                WireguardTopologyCommand::Mesh { hostname, interface } => {
+                    set_wireguard_mesh(transaction, &hostname, &interface)?
+                },
+                WireguardTopologyCommand::Spoke { hostname, mut hubs, interface } => {
+                    hubs.sort();
+                    hubs.dedup();
+                    set_wireguard_spoke(transaction, &hostname, &interface, &hubs)?
+                },
+            }
+        },
+        InfrabaseCommand::WireguardPrivkey { hostname, interface } => {
+            print_wireguard_privkey(&mut transaction, &hostname, &interface)?;
         },
-        InfrabaseCommand::WriteWireguardPeers { no_names } => {
-            write_wireguard_peers(&mut transaction, !no_names)?;
+        InfrabaseCommand::WriteWireguardPeers { no_names, dry_run, diff, remove_stale, only } => {
+            write_wireguard_peers(&mut transaction, !no_names, dry_run, diff, remove_stale, &only)?;
         },
         InfrabaseCommand::List => {
             list_machines(&mut transaction)?;
         },
+        InfrabaseCommand::Import { file, apply } => {
+            import(transaction, &file, apply)?;
+        },
+        InfrabaseCommand::Apply { file, dry_run } => {
+            apply(transaction, &file, dry_run)?;
+        },
+        InfrabaseCommand::Export => {
+            export(&mut transaction)?;
+        },
         InfrabaseCommand::NixData => {
             nix_data(&mut transaction)?;
         },
-        InfrabaseCommand::Add { hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference } => {
-            add_machine(transaction, &hostname, owner, ssh_port, ssh_user, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference)?;
+        InfrabaseCommand::Add { hostname, owner, no_ssh, ssh_port, ssh_user, no_wireguard, wireguard_interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference } => {
+            add_machine(transaction, &hostname, owner, !no_ssh, ssh_port, ssh_user, !no_wireguard, &wireguard_interface, wireguard_ipv4_address, wireguard_ipv6_address, wireguard_port, provider, provider_reference)?;
         },
         InfrabaseCommand::Remove { hostname } => {
             remove_machine(transaction, &hostname)?;
         },
-        InfrabaseCommand::SshConfig { r#for } => {
-            print_ssh_config(&mut transaction, &r#for)?;
+        InfrabaseCommand::SshConfig { r#for, from_network, aliases } => {
+            print_ssh_config(&mut transaction, r#for.as_deref(), &from_network, aliases)?;
+        },
+        InfrabaseCommand::AnsibleInventory { r#for, from_network, json, list, host } => {
+            let output = match (list, host) {
+                (true, _) => AnsibleInventoryOutput::List,
+                (false, Some(host)) => AnsibleInventoryOutput::Host(host),
+                (false, None) => AnsibleInventoryOutput::Static { json },
+            };
+            print_ansible_inventory(&mut transaction, r#for.as_deref(), &from_network, output)?;
+        },
+        InfrabaseCommand::AuthorizedKeys { r#for, nix, output_dir } => {
+            print_authorized_keys(&mut transaction, &r#for, nix, output_dir.as_deref())?;
+        },
+        InfrabaseCommand::Hosts { r#for, from_network, domain, format } => {
+            print_hosts(&mut transaction, r#for.as_deref(), &from_network, domain.as_deref(), format)?;
+        },
+        InfrabaseCommand::KnownHosts { r#for, from_network } => {
+            print_known_hosts(&mut transaction, r#for.as_deref(), &from_network)?;
         },
-        InfrabaseCommand::WgQuick { r#for } => {
-            print_wg_quick(&mut transaction, &r#for)?;
+        InfrabaseCommand::DnsZone { zone, interface, options } => {
+            print_dns_zone(&mut transaction, &zone, &interface, &options)?;
+        },
+        InfrabaseCommand::DnsReverseZones { zone, interface, rfc2317, output_dir, options } => {
+            print_reverse_dns_zones(&mut transaction, &zone, &interface, rfc2317, &options, output_dir.as_deref())?;
+        },
+        InfrabaseCommand::PrometheusSd { r#for, from_network, interface, port, best_address, output } => {
+            print_prometheus_sd(&mut transaction, r#for.as_deref(), &from_network, &interface, port, best_address, output.as_deref())?;
+        },
+        InfrabaseCommand::Networkd { r#for, interface, output_dir } => {
+            print_networkd(&mut transaction, &r#for, &interface, output_dir.as_deref())?;
+        },
+        InfrabaseCommand::Nmconnection { r#for, interface } => {
+            print_nmconnection(&mut transaction, &r#for, &interface)?;
+        },
+        InfrabaseCommand::NixosModule { r#for, private_key_file, no_names } => {
+            print_nixos_module(&mut transaction, &r#for, &private_key_file, !no_names)?;
+        },
+        InfrabaseCommand::WgQuick { r#for, interface } => {
+            print_wg_quick(&mut transaction, &r#for, &interface)?;
         },
     }
     Ok(())
@@ -1084,8 +5599,13 @@ fn main() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::{increment_ipv4_address, increment_ipv6_address};
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use super::{increment_ipv4_address, increment_ipv6_address, default_route_excluding, WireguardSetting};
+    use super::{format_known_host, parse_ssh_keyscan_line, ScannedHostKey, parse_ssh_public_key, SshPublicKey};
+    use super::{check_zone_name, get_ipv4_reverse_zones, get_ipv6_reverse_zone, ReverseZone};
+    use super::{get_wireguard_peers, Machine, MachineAddress, MachinesMap, WireguardRole, WireguardSettings};
+    use super::get_ssh_bastions;
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn test_increment_ipv4_address() {
@@ -1108,4 +5628,207 @@ mod tests {
         assert_eq!(increment_ipv6_address(&"0:0:0:0:3:ffff:ffff:ffff"               .parse::<Ipv6Addr>().unwrap()), Some("0:0:0:0:4:0:0:0"   .parse().unwrap()));
         assert_eq!(increment_ipv6_address(&"ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse::<Ipv6Addr>().unwrap()), None);
     }
+
+    #[test]
+    fn test_default_route_excluding() {
+        let ipv4 = default_route_excluding("1.2.3.4".parse().unwrap());
+        assert_eq!(ipv4.len(), 32);
+        assert_eq!(ipv4[0],  ("128.0.0.0".parse().unwrap(), 1));
+        assert_eq!(ipv4[1],  ("64.0.0.0" .parse().unwrap(), 2));
+        assert_eq!(ipv4[7],  ("0.0.0.0"  .parse().unwrap(), 8));
+        assert_eq!(ipv4[31], ("1.2.3.5"  .parse().unwrap(), 32));
+        // Every address except 1.2.3.4 is covered exactly once
+        let total: u64 = ipv4.iter().map(|(_, len)| 1u64 << (32 - len)).sum();
+        assert_eq!(total, (1u64 << 32) - 1);
+
+        let ipv6 = default_route_excluding("2001:db8::1".parse().unwrap());
+        assert_eq!(ipv6.len(), 128);
+        assert_eq!(ipv6[0],   ("8000::"     .parse().unwrap(), 1));
+        assert_eq!(ipv6[127], ("2001:db8::" .parse().unwrap(), 128));
+        assert!(ipv6.iter().all(|(ip, _)| matches!(ip, IpAddr::V6(_))));
+    }
+
+    #[test]
+    fn test_wireguard_setting_normalize_value() {
+        let normalize = |setting: &str, value: &str| setting.parse::<WireguardSetting>().unwrap().normalize_value(value).ok();
+        assert_eq!(normalize("mtu",      "1412"),                    Some("1412".to_string()));
+        assert_eq!(normalize("mtu",      "1000"),                    None);
+        assert_eq!(normalize("dns",      " 10.0.0.1, ,example.com"), Some("10.0.0.1,example.com".to_string()));
+        assert_eq!(normalize("dns",      ","),                       None);
+        assert_eq!(normalize("table",    "off"),                     Some("off".to_string()));
+        assert_eq!(normalize("table",    "main"),                    Some("main".to_string()));
+        assert_eq!(normalize("table",    "bad name"),                None);
+        assert_eq!(normalize("fwmark",   "0xca6c"),                  Some("51820".to_string()));
+        assert_eq!(normalize("fwmark",   "-1"),                      None);
+        assert_eq!(normalize("post-up",  "iptables -A FORWARD"),     Some("iptables -A FORWARD".to_string()));
+        assert_eq!(normalize("post-up",  "a\nb"),                    None);
+        assert!("listen-port".parse::<WireguardSetting>().is_err());
+    }
+
+    #[test]
+    fn test_parse_ssh_keyscan_line() {
+        assert_eq!(parse_ssh_keyscan_line("# example.com:22 SSH-2.0-OpenSSH_9.6").unwrap(), None);
+        assert_eq!(parse_ssh_keyscan_line("").unwrap(), None);
+        assert_eq!(
+            parse_ssh_keyscan_line("[2001:db8::1]:2222,web ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIH").unwrap(),
+            Some(ScannedHostKey {
+                hosts: vec!["2001:db8::1".to_string(), "web".to_string()],
+                key_type: "ssh-ed25519".to_string(),
+                public_key: "AAAAC3NzaC1lZDI1NTE5AAAAIH".to_string(),
+            })
+        );
+        assert!(parse_ssh_keyscan_line("|1|abc=|def= ssh-ed25519 AAAA").is_err());
+        assert!(parse_ssh_keyscan_line("web ssh-ed25519").is_err());
+    }
+
+    #[test]
+    fn test_format_known_host() {
+        assert_eq!(format_known_host("web", 22), "web");
+        assert_eq!(format_known_host("2001:db8::1", 2222), "[2001:db8::1]:2222");
+    }
+
+    #[test]
+    fn test_parse_ssh_public_key() {
+        assert_eq!(
+            parse_ssh_public_key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIH alice@laptop 2024").unwrap(),
+            SshPublicKey {
+                key_type: "ssh-ed25519".to_string(),
+                public_key: "AAAAC3NzaC1lZDI1NTE5AAAAIH".to_string(),
+                comment: Some("alice@laptop 2024".to_string()),
+            }
+        );
+        assert_eq!(parse_ssh_public_key("ssh-rsa AAAAB3NzaC1yc2E=\n").unwrap().comment, None);
+        assert!(parse_ssh_public_key("AAAAC3NzaC1lZDI1NTE5AAAAIH").is_err());
+        assert!(parse_ssh_public_key("ssh-ed25519 not-base64!").is_err());
+    }
+
+    #[test]
+    fn test_check_zone_name() {
+        assert_eq!(check_zone_name("example.internal").unwrap(), "example.internal");
+        assert_eq!(check_zone_name("example.internal.").unwrap(), "example.internal");
+        assert!(check_zone_name("").is_err());
+        assert!(check_zone_name("example..internal").is_err());
+        assert!(check_zone_name("-example.internal").is_err());
+        assert!(check_zone_name("my_zone.internal").is_err());
+    }
+
+    #[test]
+    fn test_get_reverse_zones() {
+        let names = |zones: Vec<ReverseZone>| zones.into_iter().map(|zone| zone.name).collect::<Vec<_>>();
+        let v4 = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+        assert_eq!(names(get_ipv4_reverse_zones(v4("10.10.0.1"), v4("10.10.0.254"), false)), vec!["0.10.10.in-addr.arpa"]);
+        assert_eq!(names(get_ipv4_reverse_zones(v4("10.10.0.1"), v4("10.10.0.254"), true)), vec!["0.10.10.in-addr.arpa"]);
+        assert_eq!(names(get_ipv4_reverse_zones(v4("10.10.0.0"), v4("10.10.255.255"), false)), vec!["10.10.in-addr.arpa"]);
+        assert_eq!(
+            names(get_ipv4_reverse_zones(v4("192.0.2.200"), v4("192.0.3.10"), true)),
+            vec!["192/26.2.0.192.in-addr.arpa", "0/28.3.0.192.in-addr.arpa"]
+        );
+        assert_eq!(
+            names(get_ipv4_reverse_zones(v4("192.0.2.200"), v4("192.0.3.10"), false)),
+            vec!["2.0.192.in-addr.arpa", "3.0.192.in-addr.arpa"]
+        );
+
+        let zone = get_ipv6_reverse_zone("fd00::1".parse().unwrap(), "fd00::ffff".parse().unwrap());
+        assert_eq!(zone.name, "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa");
+        assert_eq!(zone.record_name("fd00::12".parse().unwrap()), "2.1.0.0");
+        assert!(!zone.contains("fd00::1:0".parse().unwrap()));
+    }
+
+    /// A mesh machine on network `network`, with its address there and its WireGuard
+    /// interface listening on `wireguard_port`
+    fn test_machine(hostname: &str, network: &str, address: IpAddr, n: u8, wireguard_port: i32) -> Machine {
+        Machine {
+            hostname: hostname.to_string(),
+            wireguard_interfaces: vec![],
+            wireguard_ipv4_address: Some(Ipv4Addr::new(10, 0, 0, n)),
+            wireguard_ipv6_address: Some(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, n.into())),
+            wireguard_port: Some(wireguard_port),
+            wireguard_privkey: Some(format!("privkey{n}")),
+            wireguard_pubkey: Some(format!("pubkey{n}")),
+            exit_node: false,
+            wireguard_role: WireguardRole::Mesh,
+            wireguard_settings: WireguardSettings::default(),
+            ssh_port: Some(22),
+            ssh_user: Some("root".to_string()),
+            added_time: chrono::Utc::now(),
+            owner: "me".to_string(),
+            provider_id: None,
+            provider_reference: None,
+            networks: vec![network.to_string()],
+            addresses: vec![MachineAddress {
+                hostname: hostname.to_string(),
+                network: network.to_string(),
+                address,
+                ssh_port: Some(22),
+                // What `address add` fills in, regardless of the interfaces' ports
+                wireguard_port: Some(51820),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_get_wireguard_peers_endpoint_port() {
+        let network_links_priority_map = HashMap::from([(("internet".to_string(), "internet".to_string()), 10)]);
+        // The same two machines, loaded for each interface
+        for (interface, port) in [("wg0", 51820), ("wg1", 51821)] {
+            let machines_map: MachinesMap = [
+                test_machine("a", "internet", "192.0.2.1".parse().unwrap(), 1, port),
+                test_machine("b", "internet", "192.0.2.2".parse().unwrap(), 2, port),
+            ].into_iter().map(|m| (m.hostname.clone(), m)).collect();
+            let peers = get_wireguard_peers(
+                &machines_map, &network_links_priority_map, &HashMap::new(), &HashMap::new(), &HashMap::new(), interface, "a"
+            ).unwrap();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[0].endpoint, Some(("192.0.2.2".parse().unwrap(), u16::try_from(port).unwrap())));
+        }
+    }
+
+    #[test]
+    fn test_get_ssh_bastions() {
+        let link = |source: &str, dest: &str| ((source.to_string(), dest.to_string()), 10);
+        // laptop -> internet -> dc -> lab, and nothing reaches island
+        let network_links_priority_map = HashMap::from([
+            link("laptop", "internet"),
+            link("internet", "internet"),
+            link("internet", "dc"),
+            link("dc", "lab"),
+        ]);
+        let machines = [
+            test_machine("direct", "internet", "192.0.2.1".parse().unwrap(), 1, 51820),
+            test_machine("one-hop", "dc", "10.1.0.1".parse().unwrap(), 2, 51820),
+            test_machine("two-hops", "lab", "10.2.0.1".parse().unwrap(), 3, 51820),
+            test_machine("island", "island", "10.3.0.1".parse().unwrap(), 4, 51820),
+            test_machine("behind-island", "lab", "10.2.0.2".parse().unwrap(), 5, 51820),
+            test_machine("cycle-a", "island", "10.3.0.2".parse().unwrap(), 6, 51820),
+            test_machine("cycle-b", "island", "10.3.0.3".parse().unwrap(), 7, 51820),
+            Machine { wireguard_ipv4_address: None, ..test_machine("direct-no-wg", "internet", "192.0.2.2".parse().unwrap(), 8, 51820) },
+            test_machine("unroutable", "island", "10.3.0.4".parse().unwrap(), 9, 51820),
+            test_machine("behind-unroutable", "island", "10.3.0.5".parse().unwrap(), 10, 51820),
+            test_machine("over-wireguard", "island", "10.3.0.6".parse().unwrap(), 11, 51820),
+        ];
+        let machines = machines.iter().collect::<Vec<_>>();
+        let ssh_bastion_map = HashMap::from([
+            // Reachable through two-hops' bastion, but the override must win
+            ("behind-island".to_string(), "island".to_string()),
+            ("cycle-a".to_string(), "cycle-b".to_string()),
+            ("cycle-b".to_string(), "cycle-a".to_string()),
+            // The bastion is reachable, but can reach the machine neither directly nor over WireGuard
+            ("unroutable".to_string(), "direct-no-wg".to_string()),
+            ("behind-unroutable".to_string(), "unroutable".to_string()),
+            // The bastion can't reach the machine's address, but both are on WireGuard
+            ("over-wireguard".to_string(), "direct".to_string()),
+        ]);
+        let bastions = get_ssh_bastions(&network_links_priority_map, &ssh_bastion_map, &["laptop".to_string()], &machines);
+        assert_eq!(bastions.get("direct"), Some(&None));
+        assert_eq!(bastions.get("one-hop"), Some(&Some("direct")));
+        assert_eq!(bastions.get("two-hops"), Some(&Some("one-hop")));
+        assert_eq!(bastions.get("island"), None);
+        assert_eq!(bastions.get("behind-island"), None);
+        assert_eq!(bastions.get("cycle-a"), None);
+        assert_eq!(bastions.get("cycle-b"), None);
+        assert_eq!(bastions.get("direct-no-wg"), Some(&None));
+        assert_eq!(bastions.get("unroutable"), None);
+        assert_eq!(bastions.get("behind-unroutable"), None);
+        assert_eq!(bastions.get("over-wireguard"), Some(&Some("direct")));
+    }
 }
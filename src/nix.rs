@@ -1,43 +1,245 @@
+use std::fmt;
+
+/// A Nix value that can be rendered as Nix source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NixValue {
+    /// Attribute set, rendered with its attributes in the given order
+    Attrs(Vec<(String, NixValue)>),
+    List(Vec<NixValue>),
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Null,
+}
+
+impl NixValue {
+    pub(crate) fn attrs<K: Into<String>>(attrs: impl IntoIterator<Item = (K, NixValue)>) -> Self {
+        NixValue::Attrs(attrs.into_iter().map(|(name, value)| (name.into(), value)).collect())
+    }
+
+    /// Whether this value is rendered the same regardless of indentation
+    fn is_scalar(&self) -> bool {
+        match self {
+            NixValue::Attrs(attrs) => attrs.is_empty(),
+            NixValue::List(items) => items.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// Whether this value is short enough to be rendered on a single line: an attrset
+    /// or list containing only scalars and lists of scalars
+    fn is_inline(&self) -> bool {
+        match self {
+            NixValue::Attrs(attrs) => attrs.iter().all(|(_, value)| match value {
+                NixValue::List(items) => items.iter().all(NixValue::is_scalar),
+                value => value.is_scalar(),
+            }),
+            NixValue::List(items) => items.iter().all(NixValue::is_scalar),
+            _ => true,
+        }
+    }
+
+    /// Render as Nix source, with nested lines indented by `indent` levels of two spaces
+    pub(crate) fn render(&self, indent: usize) -> String {
+        match self {
+            NixValue::Attrs(attrs) if attrs.is_empty() => "{ }".to_string(),
+            NixValue::List(items) if items.is_empty() => "[ ]".to_string(),
+            NixValue::Attrs(attrs) if self.is_inline() => {
+                let attrs = attrs.iter()
+                    .map(|(name, value)| format!("{} = {};", attr_name(name), value.render(indent)))
+                    .collect::<Vec<_>>();
+                format!("{{ {} }}", attrs.join(" "))
+            }
+            NixValue::List(items) if self.is_inline() => {
+                let items = items.iter().map(|item| item.render(indent)).collect::<Vec<_>>();
+                format!("[ {} ]", items.join(" "))
+            }
+            NixValue::Attrs(attrs) => {
+                let inner = "  ".repeat(indent + 1);
+                let mut out = "{\n".to_string();
+                for (name, value) in attrs {
+                    out.push_str(&format!("{inner}{} = {};\n", attr_name(name), value.render(indent + 1)));
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+                out
+            }
+            NixValue::List(items) => {
+                let inner = "  ".repeat(indent + 1);
+                let mut out = "[\n".to_string();
+                for item in items {
+                    out.push_str(&format!("{inner}{}\n", item.render(indent + 1)));
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+                out
+            }
+            NixValue::String(string) => escape_string(string),
+            NixValue::Int(int) => int.to_string(),
+            NixValue::Bool(bool) => bool.to_string(),
+            NixValue::Null => "null".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for NixValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(0))
+    }
+}
+
+/// Quote a string as a Nix double-quoted string literal
+pub(crate) fn escape_string(string: &str) -> String {
+    let mut out = String::with_capacity(string.len() + 2);
+    out.push('"');
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str(r#"\""#),
+            '\\' => out.push_str(r"\\"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            // Only "${" starts an interpolation, but escaping the $ is always allowed
+            '$' if chars.peek() == Some(&'{') => out.push_str(r"\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format an attribute name, quoting it if it is not a valid Nix identifier
+pub(crate) fn attr_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &["assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with"];
+    let mut chars = name.chars();
+    let valid_identifier = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_') &&
+            chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+        }
+        None => false,
+    };
+    if valid_identifier && !KEYWORDS.contains(&name) {
+        name.to_string()
+    } else {
+        escape_string(name)
+    }
+}
+
 pub(crate) trait ToNix {
-    fn to_nix(&self) -> String;
+    fn to_nix(&self) -> NixValue;
+}
+
+impl ToNix for NixValue {
+    fn to_nix(&self) -> NixValue {
+        self.clone()
+    }
+}
+
+impl ToNix for str {
+    fn to_nix(&self) -> NixValue {
+        NixValue::String(self.to_string())
+    }
 }
 
 impl ToNix for String {
-    fn to_nix(&self) -> String {
-        // TODO: replace " with \"
-        format!(r#""{self}""#)
+    fn to_nix(&self) -> NixValue {
+        NixValue::String(self.clone())
     }
 }
 
 impl ToNix for std::net::IpAddr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> NixValue {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for std::net::Ipv4Addr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> NixValue {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for std::net::Ipv6Addr {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> NixValue {
         self.to_string().to_nix()
     }
 }
 
 impl ToNix for i32 {
-    fn to_nix(&self) -> String {
-        self.to_string()
+    fn to_nix(&self) -> NixValue {
+        NixValue::Int((*self).into())
+    }
+}
+
+impl ToNix for i64 {
+    fn to_nix(&self) -> NixValue {
+        NixValue::Int(*self)
     }
 }
 
 impl<T: ToNix> ToNix for Option<T> {
-    fn to_nix(&self) -> String {
+    fn to_nix(&self) -> NixValue {
         match self {
             Some(val) => val.to_nix(),
-            None => "null".to_string()
+            None => NixValue::Null
         }
     }
 }
+
+impl ToNix for bool {
+    fn to_nix(&self) -> NixValue {
+        NixValue::Bool(*self)
+    }
+}
+
+impl<T: ToNix> ToNix for Vec<T> {
+    fn to_nix(&self) -> NixValue {
+        NixValue::List(self.iter().map(ToNix::to_nix).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attr_name, escape_string, NixValue, ToNix};
+
+    #[test]
+    fn test_escape_string() {
+        assert_eq!(escape_string("plain"),             r#""plain""#);
+        assert_eq!(escape_string(r#"say "hi""#),       r#""say \"hi\"""#);
+        assert_eq!(escape_string(r"back\slash"),       r#""back\\slash""#);
+        assert_eq!(escape_string("${builtins.abort}"), r#""\${builtins.abort}""#);
+        assert_eq!(escape_string("$$ and $x"),         r#""$$ and $x""#);
+        assert_eq!(escape_string("a\nb\tc"),           r#""a\nb\tc""#);
+    }
+
+    #[test]
+    fn test_attr_name() {
+        assert_eq!(attr_name("host-1"),  "host-1");
+        assert_eq!(attr_name("_x'"),     "_x'");
+        assert_eq!(attr_name("1host"),   r#""1host""#);
+        assert_eq!(attr_name("a.b"),     r#""a.b""#);
+        assert_eq!(attr_name("inherit"), r#""inherit""#);
+        assert_eq!(attr_name(""),        r#""""#);
+    }
+
+    #[test]
+    fn test_value_render() {
+        let value = NixValue::attrs([
+            ("a", 1.to_nix()),
+            ("b", vec!["x".to_string()].to_nix()),
+            ("c", NixValue::List(vec![NixValue::attrs([("d", NixValue::Null)])])),
+            ("e", NixValue::Attrs(vec![])),
+        ]);
+        assert_eq!(value.to_string(), "\
+{
+  a = 1;
+  b = [ \"x\" ];
+  c = [
+    { d = null; }
+  ];
+  e = { };
+}");
+    }
+}